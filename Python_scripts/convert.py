@@ -104,6 +104,9 @@ def load_data_old_format(folder):
     with open(path.join(folder, "infos.csv"), 'r') as data_file:
         reader = csv.reader(data_file, delimiter=';')
         for row in reader:
+            # header with the scaling of the initial conditions
+            if row[0].startswith('#'):
+                continue
             all_data['t'].append(float(row[0]))
             all_data['dynamical_time'].append(float(row[1]))
             all_data['total_energy'].append(float(row[2]))
@@ -0,0 +1,208 @@
+use crate::particules::*;
+use crate::rand::Rng;
+use crate::streams::*;
+use rand_distr::StandardNormal;
+
+//number of periods drawn for the Duquennoy & Mayor distribution before giving up
+//(a_min and a_max can cut most of the distribution)
+const MAX_DRAWS: usize = 100_000;
+
+//distribution of the semi-major axis of the binaries
+#[derive(Debug, Copy, Clone)]
+pub enum SemiMajorAxis {
+    //uniform in log(a) between a_min and a_max
+    LogUniform,
+    //Duquennoy & Mayor 1991: log10(P / 1 day) ~ N(4.8, 2.3)
+    //time_unit is the N-body unit of time, in days
+    DuquennoyMayor { time_unit: f64 },
+}
+
+//parameters of the primordial binary population
+#[derive(Debug, Copy, Clone)]
+pub struct Binaries {
+    //fraction of the particules replaced by a binary
+    pub fraction: f64,
+    pub semi_major_axis: SemiMajorAxis,
+    //the semi-major axes are always drawn between a_min and a_max
+    pub a_min: f64,
+    pub a_max: f64,
+}
+
+//binary of the catalogue
+#[derive(Debug, Copy, Clone)]
+pub struct Binary {
+    //row of the binary in the catalogue
+    pub id: usize,
+    //ids of the two particules
+    pub ids: [usize; 2],
+    pub semi_major_axis: f64,
+    pub eccentricity: f64,
+}
+
+impl Binaries {
+    //draw a semi-major axis for a binary of total mass m
+    fn sample_semi_major_axis<R: Rng>(&self, rng: &mut R, m: f64) -> Result<f64, String> {
+        match self.semi_major_axis {
+            SemiMajorAxis::LogUniform => {
+                let x: f64 = rng.gen_range(0f64, 1f64);
+                Ok(self.a_min * (self.a_max / self.a_min).powf(x))
+            }
+            SemiMajorAxis::DuquennoyMayor { time_unit } => {
+                for _ in 0..MAX_DRAWS {
+                    let g: f64 = rng.sample(StandardNormal);
+                    let period = 10f64.powf(4.8 + 2.3 * g) / time_unit;
+                    //Kepler's third law, G = 1
+                    let a =
+                        (m * period * period / (4. * std::f64::consts::PI.powf(2.))).powf(1. / 3.);
+                    if a > self.a_min && a < self.a_max {
+                        return Ok(a);
+                    }
+                }
+                Err(format!(
+                    "no semi-major axis between {} and {} in {} draws of the period (mass {}), check binary_time_unit",
+                    self.a_min, self.a_max, MAX_DRAWS, m
+                ))
+            }
+        }
+    }
+}
+
+//random rotation matrix, from a uniform random quaternion
+fn random_rotation<R: Rng>(rng: &mut R) -> [[f64; 3]; 3] {
+    let u1: f64 = rng.gen_range(0f64, 1f64);
+    let u2: f64 = 2. * std::f64::consts::PI * rng.gen_range(0f64, 1f64);
+    let u3: f64 = 2. * std::f64::consts::PI * rng.gen_range(0f64, 1f64);
+    let (w, x, y, z) = (
+        (1. - u1).sqrt() * u2.sin(),
+        (1. - u1).sqrt() * u2.cos(),
+        u1.sqrt() * u3.sin(),
+        u1.sqrt() * u3.cos(),
+    );
+    [
+        [
+            1. - 2. * (y * y + z * z),
+            2. * (x * y - z * w),
+            2. * (x * z + y * w),
+        ],
+        [
+            2. * (x * y + z * w),
+            1. - 2. * (x * x + z * z),
+            2. * (y * z - x * w),
+        ],
+        [
+            2. * (x * z - y * w),
+            2. * (y * z + x * w),
+            1. - 2. * (x * x + y * y),
+        ],
+    ]
+}
+
+//relative position and speed of a keplerian orbit of total mass m, at the mean anomaly,
+//in the plane of the orbit (the pericenter is along x)
+pub fn orbit_in_plane(m: f64, a: f64, e: f64, mean_anomaly: f64) -> ([f64; 3], [f64; 3]) {
+    //solve Kepler's equation M = E - e sin(E) with Newton's method
+    let mut ecc_anomaly = mean_anomaly;
+    for _ in 0..50 {
+        let delta =
+            (ecc_anomaly - e * ecc_anomaly.sin() - mean_anomaly) / (1. - e * ecc_anomaly.cos());
+        ecc_anomaly -= delta;
+        if delta.abs() < 1e-12 {
+            break;
+        }
+    }
+    let (sin, cos) = ecc_anomaly.sin_cos();
+    let b = (1. - e * e).sqrt();
+    let v = (m / a).sqrt() / (1. - e * cos);
+    (
+        [a * (cos - e), a * b * sin, 0.],
+        [-v * sin, v * b * cos, 0.],
+    )
+}
+
+//relative position and speed of a keplerian orbit of total mass m, at a random time
+fn kepler_orbit<R: Rng>(rng: &mut R, m: f64, a: f64, e: f64) -> ([f64; 3], [f64; 3]) {
+    let mean_anomaly = 2. * std::f64::consts::PI * rng.gen_range(0f64, 1f64);
+    let (position, speed) = orbit_in_plane(m, a, e, mean_anomaly);
+
+    let rotation = random_rotation(rng);
+    let mut r = [0f64; 3];
+    let mut s = [0f64; 3];
+    for i in 0..3 {
+        for j in 0..3 {
+            r[i] += rotation[i][j] * position[j];
+            s[i] += rotation[i][j] * speed[j];
+        }
+    }
+    (r, s)
+}
+
+//return the semi-major axis and the eccentricity of two particules
+pub fn orbital_elements(p1: &Particule, p2: &Particule) -> (f64, f64) {
+    let m = p1.mass + p2.mass;
+    let r: Vec<f64> = (0..3).map(|i| p1.position[i] - p2.position[i]).collect();
+    let v: Vec<f64> = (0..3).map(|i| p1.speed[i] - p2.speed[i]).collect();
+    let d = r.iter().map(|x| x * x).sum::<f64>().sqrt();
+    let v2 = v.iter().map(|x| x * x).sum::<f64>();
+    //specific orbital energy and angular momentum
+    let energy = 0.5 * v2 - m / d;
+    let h = [
+        r[1] * v[2] - r[2] * v[1],
+        r[2] * v[0] - r[0] * v[2],
+        r[0] * v[1] - r[1] * v[0],
+    ];
+    let h2 = h.iter().map(|x| x * x).sum::<f64>();
+    let a = -0.5 * m / energy;
+    let e = f64::max(1. + 2. * energy * h2 / (m * m), 0.).sqrt();
+    (a, e)
+}
+
+//replace a fraction of the particules by binaries
+//each binary has the mass of the particule it replaces, split equally between the two stars,
+//and its center of mass is put where the particule was
+//the first star keeps the id of the particule, the second is added at the end
+//return the catalogue of the binaries
+pub fn make_binaries(
+    particules: &mut Vec<Particule>,
+    binaries: &Binaries,
+    seed: u64,
+) -> Result<Vec<Binary>, String> {
+    let nb = particules.len();
+    let nb_binaries = (binaries.fraction * nb as f64).round() as usize;
+    let ids = par_sample(nb, nb_binaries, stream_seed(seed, STREAM_BINARIES));
+    //the orbits are drawn in parallel, then the particules are split
+    let orbits = par_generation(nb_binaries, stream_seed(seed, STREAM_ORBITS), |rng, k| {
+        let m = particules[ids[k]].mass;
+        let a = binaries.sample_semi_major_axis(rng, m)?;
+        //thermal distribution f(e) = 2e
+        let e = rng.gen_range(0f64, 1f64).sqrt();
+        let (r, v) = kepler_orbit(rng, m, a, e);
+        Ok((a, e, r, v))
+    })
+    .into_iter()
+    .collect::<Result<Vec<_>, String>>()?;
+    let mut catalogue = Vec::with_capacity(nb_binaries);
+
+    for (&id, &(a, e, r, v)) in ids.iter().zip(orbits.iter()) {
+        let p = particules[id];
+        let mut p1 = p;
+        let mut p2 = p;
+        p1.mass = 0.5 * p.mass;
+        p2.mass = 0.5 * p.mass;
+        for i in 0..3 {
+            p1.position[i] += 0.5 * r[i];
+            p2.position[i] -= 0.5 * r[i];
+            p1.speed[i] += 0.5 * v[i];
+            p2.speed[i] -= 0.5 * v[i];
+        }
+        particules[id] = p1;
+        particules.push(p2);
+        catalogue.push(Binary {
+            id: catalogue.len(),
+            ids: [id, particules.len() - 1],
+            semi_major_axis: a,
+            eccentricity: e,
+        });
+    }
+    println!("{} primordial binaries", catalogue.len());
+    Ok(catalogue)
+}
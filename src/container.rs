@@ -0,0 +1,84 @@
+//spherical box of fixed radius, centered on the origin of the model
+//the particules are reflected elastically by the wall
+#[derive(Debug, Copy, Clone)]
+pub struct Container {
+    pub radius: f64,
+    //total number of reflections on the wall
+    pub crossings: usize,
+    //momentum given to the wall and time since the last measure of the pressure
+    pub impulse: f64,
+    pub time: f64,
+    //pressure on the wall, mean over the last output interval
+    pub pressure: f64,
+}
+
+impl Container {
+    pub fn new(radius: f64) -> Container {
+        Container {
+            radius: radius,
+            crossings: 0,
+            impulse: 0.,
+            time: 0.,
+            pressure: 0.,
+        }
+    }
+
+    //move a particule in straight line during dt, with the reflections on the wall
+    //at the exact time of the crossing
+    //position is relative to the center of the container
+    //return the number of reflections and the momentum per unit mass given to the wall
+    pub fn drift(&self, position: &mut [f64; 3], speed: &mut [f64; 3], dt: f64) -> (usize, f64) {
+        let mut remaining = dt;
+        let mut crossings = 0;
+        let mut impulse = 0.;
+        //a particule can't cross the wall many times in one step, except with a huge dt
+        for _ in 0..1000 {
+            //time of the crossing: |x + v tau| = R, with x inside the sphere
+            let a: f64 = speed.iter().map(|v| v * v).sum();
+            let b: f64 = 2. * (0..3).map(|i| position[i] * speed[i]).sum::<f64>();
+            let c: f64 = position.iter().map(|x| x * x).sum::<f64>() - self.radius * self.radius;
+            let tau = if a > 0. {
+                (-b + f64::max(b * b - 4. * a * c, 0.).sqrt()) / (2. * a)
+            } else {
+                f64::INFINITY
+            };
+            if tau >= remaining {
+                break;
+            }
+            let tau = f64::max(tau, 0.);
+            for i in 0..3 {
+                position[i] += tau * speed[i];
+            }
+            //specular reflection
+            let r = position.iter().map(|x| x * x).sum::<f64>().sqrt();
+            let n = [position[0] / r, position[1] / r, position[2] / r];
+            let v_r: f64 = (0..3).map(|i| speed[i] * n[i]).sum();
+            if v_r > 0. {
+                for i in 0..3 {
+                    speed[i] -= 2. * v_r * n[i];
+                }
+                impulse += 2. * v_r;
+                crossings += 1;
+            }
+            remaining -= tau;
+        }
+        for i in 0..3 {
+            position[i] += remaining * speed[i];
+        }
+        (crossings, impulse)
+    }
+
+    //pressure on the wall since the last measure: momentum / (area * time)
+    pub fn measure_pressure(&mut self) {
+        if self.time > 0. {
+            self.pressure =
+                self.impulse / (4. * std::f64::consts::PI * self.radius * self.radius * self.time);
+        }
+        self.impulse = 0.;
+        self.time = 0.;
+    }
+
+    pub fn volume(&self) -> f64 {
+        4. / 3. * std::f64::consts::PI * self.radius.powf(3.)
+    }
+}
@@ -0,0 +1,105 @@
+use crate::particules::*;
+use crate::rayon::prelude::*;
+use crate::shells::*;
+use crate::softening::*;
+use crate::streams::*;
+use crate::tree::*;
+
+//exact acceleration and potential by direct summation over all the pairs, O(N^2)
+//same conventions as the tree: the pairs use the largest of the two softenings
+//(softenings empty if all the particules use epsilon)
+
+//acceleration (ap[0], ap[1], ap[2]) and potential ap[3] of the particule p_id
+pub fn direct_acceleration_of(
+    particules: &[Particule],
+    p_id: usize,
+    kernel: Kernel,
+    epsilon: f64,
+    softenings: &[f64],
+) -> [f64; 4] {
+    let p = &particules[p_id];
+    let mut ap = [0f64; 4];
+    for (q_id, q) in particules.iter().enumerate() {
+        if q_id == p_id {
+            continue;
+        }
+        let d = p
+            .position
+            .iter()
+            .zip(q.position.iter())
+            .map(|(a, b)| (a - b) * (a - b))
+            .sum::<f64>()
+            .sqrt();
+        let epsilon = if softenings.is_empty() {
+            epsilon
+        } else {
+            f64::max(softenings[p_id], softenings[q_id])
+        };
+        let (f, phi) = kernel.kernel(d, epsilon);
+        for i in 0..3 {
+            ap[i] += q.mass * f * (q.position[i] - p.position[i]);
+        }
+        ap[3] += q.mass * phi;
+    }
+    ap
+}
+
+//acceleration and potential of all the particules, in parallel
+pub fn direct_acceleration(
+    particules: &[Particule],
+    kernel: Kernel,
+    epsilon: f64,
+    softenings: &[f64],
+) -> Vec<[f64; 4]> {
+    (0..particules.len())
+        .into_par_iter()
+        .map(|p_id| direct_acceleration_of(particules, p_id, kernel, epsilon, softenings))
+        .collect()
+}
+
+//compare the accelerations of the particules (from the solver of the tree) to the exact ones,
+//for nb particules drawn at random from the seed of the run (the exact ones include
+//the analytic halo)
+//return the [median, 99th percentile, max] of the relative errors
+//and the mean number of interactions per particule of the solver
+//(cell-cell and particule-particule ones for the fast multipole method,
+//the short range ones for TreePM, 0 for the mesh alone)
+pub fn force_errors(tree: &Tree, nb: usize) -> ([f64; 3], f64) {
+    let nb_particules = tree.particules.len();
+    let ids = par_sample(
+        nb_particules,
+        usize::min(nb, nb_particules),
+        stream_seed(tree.seed, STREAM_FORCE_CHECK),
+    );
+    let (mut errors, interactions): (Vec<f64>, Vec<usize>) = ids
+        .par_iter()
+        .map(|&p_id| {
+            let p = &tree.particules[p_id];
+            let ap = direct_acceleration_of(
+                &tree.particules,
+                p_id,
+                tree.kernel,
+                tree.epsilon,
+                &tree.softenings,
+            );
+            let halo = tree.halo_acceleration(&p.position);
+            let a: Vec<f64> = (0..3).map(|i| ap[i] + halo[i]).collect();
+            let a_norm = a.iter().map(|a| a * a).sum::<f64>().sqrt();
+            let da = (0..3)
+                .map(|i| (p.acceleration[i] - a[i]) * (p.acceleration[i] - a[i]))
+                .sum::<f64>()
+                .sqrt();
+            let interactions = match tree.solver {
+                Solver::Tree | Solver::TreePm(_, _) => tree.tree_acceleration(p_id).1,
+                Solver::Pm(_) | Solver::Scf(_) | Solver::Spherical => 0,
+                _ => nb_particules - 1,
+            };
+            (da / a_norm, interactions)
+        })
+        .unzip();
+    let mean_interactions = match tree.solver {
+        Solver::Fmm(_) => tree.fmm_interactions,
+        _ => interactions.iter().sum::<usize>() as f64 / interactions.len() as f64,
+    };
+    (quantiles(&mut errors), mean_interactions)
+}
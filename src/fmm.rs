@@ -0,0 +1,652 @@
+use crate::rayon::prelude::*;
+use crate::softening::*;
+use crate::tree::*;
+
+//fast multipole method on the octree (Dehnen 2002), with cartesian expansions of order p
+//potential phi(x) = -sum m_j G(x - x_j), G = 1 / r (1 / sqrt(r^2 + eps^2) with the Plummer kernel)
+//multipoles of a node around its center of mass z: M_n = sum m_j (x_j - z)^n / n!
+//local expansion of a node: phi(x) = -sum L_k (x - z)^k / k!, so the acceleration is L_(k + e_i)
+//the cell-cell interactions keep the terms |k| + |m| <= p of both directions,
+//so the forces between two cells are opposite and the momentum is conserved
+
+//depth of the tree under which the walk is not parallel anymore
+const PARALLEL_DEPTH: usize = 4;
+//highest order of the expansions, and number of multi-indices for this order
+pub const MAX_ORDER: usize = 8;
+const MAX_COEFFICIENTS: usize = (MAX_ORDER + 1) * (MAX_ORDER + 2) * (MAX_ORDER + 3) / 6;
+
+//multi-indices n = (nx, ny, nz) with |n| <= p, and the terms of the operations on the expansions
+pub struct Expansion {
+    pub order: usize,
+    pub indices: Vec<[usize; 3]>,
+    //index of the multi-index (nx, ny, nz) in indices
+    table: Vec<usize>,
+    //terms of the derivative D_n of a radial function G:
+    //D_n = sum g_j * coefficient * R^e / e!, g_j the j-th derivative of G with respect to r^2 / 2
+    derivatives: Vec<Vec<(usize, f64, usize)>>,
+    //(n, m, n - m) for m <= n, used by the shifts of the expansions
+    shifts: Vec<(usize, usize, usize)>,
+    //(k, m, k + m, (-1)^|m|) for |k| + |m| <= p, used by the cell-cell interactions
+    //(the dipole of a node is 0 around its center of mass, so |m| = 1 is skipped)
+    interactions: Vec<(usize, usize, usize, f64)>,
+    //the same terms when the target is a leaf (|k| <= 1) or the source is a leaf (m = 0)
+    to_leaf: Vec<(usize, usize, usize, f64)>,
+    from_leaf: Vec<(usize, usize, usize, f64)>,
+}
+
+fn factorial(n: usize) -> f64 {
+    (1..=n).map(|i| i as f64).product()
+}
+
+impl Expansion {
+    pub fn new(order: usize) -> Expansion {
+        let p = order;
+        let mut indices = Vec::new();
+        let mut table = vec![usize::MAX; (p + 1) * (p + 1) * (p + 1)];
+        for degree in 0..=p {
+            for nx in (0..=degree).rev() {
+                for ny in (0..=degree - nx).rev() {
+                    let nz = degree - nx - ny;
+                    table[(nx * (p + 1) + ny) * (p + 1) + nz] = indices.len();
+                    indices.push([nx, ny, nz]);
+                }
+            }
+        }
+        let index = |n: [usize; 3]| table[(n[0] * (p + 1) + n[1]) * (p + 1) + n[2]];
+        let degree = |n: &[usize; 3]| n[0] + n[1] + n[2];
+
+        //each pair of derivatives along the same axis can give a kronecker delta:
+        //D_n = sum over k <= n / 2 of g_(|n| - |k|) prod_i n_i! / (2^k_i k_i!) R_i^(n_i - 2 k_i) / (n_i - 2 k_i)!
+        let derivatives = indices
+            .iter()
+            .map(|n| {
+                let mut terms = Vec::new();
+                for kx in 0..=n[0] / 2 {
+                    for ky in 0..=n[1] / 2 {
+                        for kz in 0..=n[2] / 2 {
+                            let k = [kx, ky, kz];
+                            let coefficient: f64 = (0..3)
+                                .map(|i| {
+                                    factorial(n[i]) / (2f64.powi(k[i] as i32) * factorial(k[i]))
+                                })
+                                .product();
+                            let e = [n[0] - 2 * kx, n[1] - 2 * ky, n[2] - 2 * kz];
+                            terms.push((degree(n) - degree(&k), coefficient, index(e)));
+                        }
+                    }
+                }
+                terms
+            })
+            .collect();
+
+        let mut shifts = Vec::new();
+        let mut interactions = Vec::new();
+        for (n_id, n) in indices.iter().enumerate() {
+            for (m_id, m) in indices.iter().enumerate() {
+                if (0..3).all(|i| m[i] <= n[i]) {
+                    shifts.push((n_id, m_id, index([n[0] - m[0], n[1] - m[1], n[2] - m[2]])));
+                }
+                if degree(n) + degree(m) <= p && degree(m) != 1 {
+                    let sign = if degree(m) % 2 == 0 { 1. } else { -1. };
+                    interactions.push((
+                        n_id,
+                        m_id,
+                        index([n[0] + m[0], n[1] + m[1], n[2] + m[2]]),
+                        sign,
+                    ));
+                }
+            }
+        }
+
+        let to_leaf = interactions
+            .iter()
+            .filter(|t| degree(&indices[t.0]) <= 1)
+            .cloned()
+            .collect();
+        let from_leaf = interactions.iter().filter(|t| t.1 == 0).cloned().collect();
+
+        Expansion {
+            order: order,
+            indices: indices,
+            table: table,
+            derivatives: derivatives,
+            shifts: shifts,
+            interactions: interactions,
+            to_leaf: to_leaf,
+            from_leaf: from_leaf,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.indices.len()
+    }
+
+    //index of the multi-index n
+    pub fn index(&self, n: [usize; 3]) -> usize {
+        let p = self.order;
+        self.table[(n[0] * (p + 1) + n[1]) * (p + 1) + n[2]]
+    }
+
+    //v^n / n! for all the multi-indices
+    fn monomials(&self, v: &[f64; 3], monomials: &mut [f64; MAX_COEFFICIENTS]) {
+        let p = self.order;
+        let mut powers = [[1f64; 3]; MAX_ORDER + 1];
+        for j in 1..=p {
+            for i in 0..3 {
+                powers[j][i] = powers[j - 1][i] * v[i] / j as f64;
+            }
+        }
+        for (monomial, n) in monomials.iter_mut().zip(self.indices.iter()) {
+            *monomial = powers[n[0]][0] * powers[n[1]][1] * powers[n[2]][2];
+        }
+    }
+
+    //derivatives D_n of G at R, with G = 1 / sqrt(r^2 + eps^2)
+    fn green_derivatives(
+        &self,
+        r: &[f64; 3],
+        epsilon: f64,
+        derivatives: &mut [f64; MAX_COEFFICIENTS],
+    ) {
+        let s2 = r[0] * r[0] + r[1] * r[1] + r[2] * r[2] + epsilon * epsilon;
+        //g_j = (-1)^j (2j - 1)!! / s^(2j + 1)
+        let mut g = [1. / s2.sqrt(); MAX_ORDER + 1];
+        for j in 1..=self.order {
+            g[j] = -g[j - 1] * (2 * j - 1) as f64 / s2;
+        }
+        let mut monomials = [0f64; MAX_COEFFICIENTS];
+        self.monomials(r, &mut monomials);
+        for (derivative, terms) in derivatives.iter_mut().zip(self.derivatives.iter()) {
+            *derivative = terms
+                .iter()
+                .map(|&(j, coefficient, e)| g[j] * coefficient * monomials[e])
+                .sum();
+        }
+    }
+
+    //add to the multipoles of a node those of a kid whose center of mass is at s from its own
+    fn shift_multipoles(&self, node: &mut [f64], kid: &[f64], s: &[f64; 3]) {
+        let mut monomials = [0f64; MAX_COEFFICIENTS];
+        self.monomials(s, &mut monomials);
+        for &(n, m, d) in self.shifts.iter() {
+            node[n] += kid[m] * monomials[d];
+        }
+    }
+
+    //add to the local expansion of a kid the one of its mother, whose center of mass is at -t
+    fn shift_local(&self, kid: &mut [f64], node: &[f64], t: &[f64; 3]) {
+        let mut monomials = [0f64; MAX_COEFFICIENTS];
+        self.monomials(t, &mut monomials);
+        for &(k, n, d) in self.shifts.iter() {
+            kid[n] += node[k] * monomials[d];
+        }
+    }
+
+    //add to the local expansion of the target the field of the multipoles of the source,
+    //r the position of the target relative to the source
+    fn multipoles_to_local(
+        &self,
+        local: &mut [f64],
+        multipoles: &[f64],
+        r: &[f64; 3],
+        epsilon: f64,
+        terms: &[(usize, usize, usize, f64)],
+    ) {
+        let mut derivatives = [0f64; MAX_COEFFICIENTS];
+        self.green_derivatives(r, epsilon, &mut derivatives);
+        for &(k, m, km, sign) in terms.iter() {
+            local[k] += sign * multipoles[m] * derivatives[km];
+        }
+    }
+}
+
+//pairs of interacting nodes
+type Pairs = Vec<(u32, u32)>;
+
+//walk of the pairs of nodes, from the interaction of the root with itself
+struct Walk<'a> {
+    tree: &'a Tree,
+    radii: &'a [f64],
+    //number of particules of the nodes
+    counts: &'a [usize],
+    //two nodes with less pairs of particules than that are computed by direct summation
+    direct_pairs: usize,
+}
+
+impl<'a> Walk<'a> {
+    fn is_leaf(&self, id: usize) -> bool {
+        self.tree.nodes[id].particule.is_some()
+    }
+
+    fn kids(&self, id: usize) -> impl Iterator<Item = usize> + 'a {
+        let nodes = &self.tree.nodes;
+        nodes[id]
+            .kids
+            .iter()
+            .filter_map(|k| *k)
+            .map(|k| k as usize)
+            .filter(move |&k| nodes[k].mass > 0.)
+    }
+
+    //all the pairs of particules of two nodes
+    fn leaf_pairs(&self, a: usize, b: usize, leaves: &mut Vec<(u32, u32)>) {
+        if !self.is_leaf(a) {
+            for kid in self.kids(a) {
+                self.leaf_pairs(kid, b, leaves);
+            }
+        } else if !self.is_leaf(b) {
+            for kid in self.kids(b) {
+                self.leaf_pairs(a, kid, leaves);
+            }
+        } else {
+            leaves.push((a as u32, b as u32));
+        }
+    }
+
+    //softening of the interaction between two nodes
+    fn epsilon(&self, a: usize, b: usize) -> f64 {
+        if self.tree.softenings.is_empty() {
+            self.tree.epsilon
+        } else {
+            f64::max(self.tree.nodes[a].softening, self.tree.nodes[b].softening)
+        }
+    }
+
+    //the expansions of the two nodes converge: d * theta > r_a + r_b
+    //with a kernel of finite support, the particules of the two nodes must also be farther
+    //than the support, where the interaction is newtonian
+    fn well_separated(&self, a: usize, b: usize) -> bool {
+        let (na, nb) = (&self.tree.nodes[a], &self.tree.nodes[b]);
+        let d = (0..3)
+            .map(|i| (na.center_of_mass[i] - nb.center_of_mass[i]).powi(2))
+            .sum::<f64>()
+            .sqrt();
+        let r = self.radii[a] + self.radii[b];
+        let support = self.tree.kernel.support(self.epsilon(a, b));
+        d * self.tree.theta > r && (!support.is_finite() || d - r > support)
+    }
+
+    //interaction of two different nodes
+    fn interact(
+        &self,
+        a: usize,
+        b: usize,
+        cells: &mut Vec<(u32, u32)>,
+        leaves: &mut Vec<(u32, u32)>,
+    ) {
+        let (leaf_a, leaf_b) = (self.is_leaf(a), self.is_leaf(b));
+        if leaf_a && leaf_b {
+            leaves.push((a as u32, b as u32));
+        } else if self.well_separated(a, b) {
+            //small nodes are cheaper by direct summation
+            if self.counts[a] * self.counts[b] < self.direct_pairs {
+                self.leaf_pairs(a, b, leaves);
+            } else {
+                cells.push((a as u32, b as u32));
+            }
+        } else if !leaf_a && (leaf_b || self.radii[a] >= self.radii[b]) {
+            for kid in self.kids(a) {
+                self.interact(kid, b, cells, leaves);
+            }
+        } else {
+            for kid in self.kids(b) {
+                self.interact(a, kid, cells, leaves);
+            }
+        }
+    }
+
+    //interaction of a node with itself: the kids with themselves and with each other
+    //return the pairs of nodes (cells) and of leaves (particules) interacting
+    fn self_interact(&self, a: usize, depth: usize) -> (Pairs, Pairs) {
+        let kids: Vec<usize> = self.kids(a).collect();
+        let mut tasks = Vec::new();
+        for (i, &kid) in kids.iter().enumerate() {
+            tasks.push((kid, None));
+            for &other in kids[i + 1..].iter() {
+                tasks.push((kid, Some(other)));
+            }
+        }
+        let task = |&(kid, other): &(usize, Option<usize>)| match other {
+            None => self.self_interact(kid, depth + 1),
+            Some(other) => {
+                let (mut cells, mut leaves) = (Vec::new(), Vec::new());
+                self.interact(kid, other, &mut cells, &mut leaves);
+                (cells, leaves)
+            }
+        };
+        let concat = |mut x: (Pairs, Pairs), y: (Pairs, Pairs)| {
+            x.0.extend(y.0);
+            x.1.extend(y.1);
+            x
+        };
+        if depth < PARALLEL_DEPTH {
+            tasks
+                .par_iter()
+                .map(task)
+                .reduce(|| (Vec::new(), Vec::new()), concat)
+        } else {
+            tasks
+                .iter()
+                .map(task)
+                .fold((Vec::new(), Vec::new()), concat)
+        }
+    }
+}
+
+//for each node, the list of the nodes it interacts with (each pair is used in both directions)
+fn neighbors(nb_nodes: usize, pairs: &[(u32, u32)]) -> Vec<Vec<u32>> {
+    let mut neighbors = vec![Vec::new(); nb_nodes];
+    for &(a, b) in pairs.iter() {
+        neighbors[a as usize].push(b);
+        neighbors[b as usize].push(a);
+    }
+    neighbors
+}
+
+//acceleration and potential of all the particules with the fast multipole method
+//return them with the mean number of interactions (cell-cell and particule-particule) per particule
+pub fn fmm_acceleration(tree: &Tree, expansion: &Expansion) -> (Vec<[f64; 4]>, f64) {
+    let nb_nodes = tree.nodes.len();
+    let nb_coefficients = expansion.len();
+
+    //nodes by level, and their mother
+    let mut mothers = vec![0usize; nb_nodes];
+    let mut levels = vec![vec![0usize]];
+    loop {
+        let mut level = Vec::new();
+        for &id in levels.last().unwrap().iter() {
+            for kid in tree.nodes[id].kids.iter().filter_map(|k| *k) {
+                mothers[kid as usize] = id;
+                level.push(kid as usize);
+            }
+        }
+        if level.is_empty() {
+            break;
+        }
+        levels.push(level);
+    }
+
+    //upward pass: multipoles and radii (distance from the center of mass to the farthest particule)
+    let mut multipoles = vec![0f64; nb_nodes * nb_coefficients];
+    let mut radii = vec![0f64; nb_nodes];
+    let mut counts = vec![0usize; nb_nodes];
+    for level in levels.iter().rev() {
+        let results: Vec<(Vec<f64>, f64, usize)> = level
+            .par_iter()
+            .map(|&id| {
+                let n = &tree.nodes[id];
+                let mut m = vec![0f64; nb_coefficients];
+                let mut radius = 0f64;
+                let mut count = 0;
+                if n.particule.is_some() {
+                    m[0] = n.mass;
+                    count = 1;
+                }
+                for kid in n.kids.iter().filter_map(|k| *k).map(|k| k as usize) {
+                    let s = [
+                        tree.nodes[kid].center_of_mass[0] - n.center_of_mass[0],
+                        tree.nodes[kid].center_of_mass[1] - n.center_of_mass[1],
+                        tree.nodes[kid].center_of_mass[2] - n.center_of_mass[2],
+                    ];
+                    expansion.shift_multipoles(
+                        &mut m,
+                        &multipoles[kid * nb_coefficients..(kid + 1) * nb_coefficients],
+                        &s,
+                    );
+                    let d = (s[0] * s[0] + s[1] * s[1] + s[2] * s[2]).sqrt();
+                    radius = f64::max(radius, d + radii[kid]);
+                    count += counts[kid];
+                }
+                (m, radius, count)
+            })
+            .collect();
+        for (&id, (m, radius, count)) in level.iter().zip(results) {
+            multipoles[id * nb_coefficients..(id + 1) * nb_coefficients].copy_from_slice(&m);
+            radii[id] = radius;
+            counts[id] = count;
+        }
+    }
+
+    //pairs of interacting nodes
+    let walk = Walk {
+        tree: tree,
+        radii: &radii,
+        counts: &counts,
+        direct_pairs: nb_coefficients / 2,
+    };
+    let (cells, leaves) = walk.self_interact(0, 0);
+    let interactions = 2. * (cells.len() + leaves.len()) as f64 / tree.particules.len() as f64;
+    let cells = neighbors(nb_nodes, &cells);
+    let leaves = neighbors(nb_nodes, &leaves);
+
+    //cell-cell interactions
+    let mut locals = vec![0f64; nb_nodes * nb_coefficients];
+    locals
+        .par_chunks_mut(nb_coefficients)
+        .enumerate()
+        .for_each(|(id, local)| {
+            let n = &tree.nodes[id];
+            for &other in cells[id].iter() {
+                let other = other as usize;
+                let o = &tree.nodes[other];
+                let r = [
+                    n.center_of_mass[0] - o.center_of_mass[0],
+                    n.center_of_mass[1] - o.center_of_mass[1],
+                    n.center_of_mass[2] - o.center_of_mass[2],
+                ];
+                let epsilon = match tree.kernel {
+                    Kernel::Plummer => walk.epsilon(id, other),
+                    _ => 0.,
+                };
+                let terms = if n.particule.is_some() {
+                    &expansion.to_leaf
+                } else if o.particule.is_some() {
+                    &expansion.from_leaf
+                } else {
+                    &expansion.interactions
+                };
+                expansion.multipoles_to_local(
+                    local,
+                    &multipoles[other * nb_coefficients..(other + 1) * nb_coefficients],
+                    &r,
+                    epsilon,
+                    terms,
+                );
+            }
+        });
+
+    //downward pass: the local expansions of the mothers are added to the kids
+    for level in levels.iter().skip(1) {
+        let results: Vec<Vec<f64>> = level
+            .par_iter()
+            .map(|&id| {
+                let mother = mothers[id];
+                let mut local = locals[id * nb_coefficients..(id + 1) * nb_coefficients].to_vec();
+                let t = [
+                    tree.nodes[id].center_of_mass[0] - tree.nodes[mother].center_of_mass[0],
+                    tree.nodes[id].center_of_mass[1] - tree.nodes[mother].center_of_mass[1],
+                    tree.nodes[id].center_of_mass[2] - tree.nodes[mother].center_of_mass[2],
+                ];
+                expansion.shift_local(
+                    &mut local,
+                    &locals[mother * nb_coefficients..(mother + 1) * nb_coefficients],
+                    &t,
+                );
+                local
+            })
+            .collect();
+        for (&id, local) in level.iter().zip(results) {
+            locals[id * nb_coefficients..(id + 1) * nb_coefficients].copy_from_slice(&local);
+        }
+    }
+
+    //the center of mass of a leaf is its particule: the local expansion gives the acceleration
+    //and the potential directly, the neighbor particules are added by direct summation
+    let e = [
+        expansion.index([1, 0, 0]),
+        expansion.index([0, 1, 0]),
+        expansion.index([0, 0, 1]),
+    ];
+    let results: Vec<(usize, [f64; 4])> = (0..nb_nodes)
+        .into_par_iter()
+        .filter_map(|id| tree.nodes[id].particule.map(|p_id| (id, p_id as usize)))
+        .map(|(id, p_id)| {
+            let local = &locals[id * nb_coefficients..(id + 1) * nb_coefficients];
+            let mut ap = [local[e[0]], local[e[1]], local[e[2]], -local[0]];
+            let p = &tree.particules[p_id];
+            for &other in leaves[id].iter() {
+                let q_id = tree.nodes[other as usize].particule.unwrap() as usize;
+                let q = &tree.particules[q_id];
+                let d = (0..3)
+                    .map(|i| (p.position[i] - q.position[i]).powi(2))
+                    .sum::<f64>()
+                    .sqrt();
+                let epsilon = if tree.softenings.is_empty() {
+                    tree.epsilon
+                } else {
+                    f64::max(tree.softenings[p_id], tree.softenings[q_id])
+                };
+                let (f, phi) = tree.kernel.kernel(d, epsilon);
+                for i in 0..3 {
+                    ap[i] += q.mass * f * (q.position[i] - p.position[i]);
+                }
+                ap[3] += q.mass * phi;
+            }
+            (p_id, ap)
+        })
+        .collect();
+
+    let mut aps = vec![[0f64; 4]; tree.particules.len()];
+    for (p_id, ap) in results.into_iter() {
+        aps[p_id] = ap;
+    }
+    (aps, interactions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::direct::*;
+
+    //potential at x of the multipoles around z: phi = -sum (-1)^|n| M_n D_n(x - z)
+    fn multipole_potential(expansion: &Expansion, multipoles: &[f64], r: &[f64; 3]) -> f64 {
+        let mut derivatives = [0f64; MAX_COEFFICIENTS];
+        expansion.green_derivatives(r, 0., &mut derivatives);
+        -expansion
+            .indices
+            .iter()
+            .enumerate()
+            .map(|(id, n)| {
+                let sign = if (n[0] + n[1] + n[2]) % 2 == 0 {
+                    1.
+                } else {
+                    -1.
+                };
+                sign * multipoles[id] * derivatives[id]
+            })
+            .sum::<f64>()
+    }
+
+    #[test]
+    fn shifted_multipoles_match_the_direct_sum() {
+        let expansion = Expansion::new(MAX_ORDER);
+        let masses = [1., 0.5, 2., 0.7];
+        let positions = [
+            [0.1, -0.2, 0.3],
+            [-0.3, 0.1, 0.05],
+            [0.2, 0.25, -0.1],
+            [-0.05, -0.3, -0.2],
+        ];
+        //multipoles around z by direct summation
+        let multipoles = |z: &[f64; 3]| {
+            let mut multipoles = vec![0f64; expansion.len()];
+            let mut monomials = [0f64; MAX_COEFFICIENTS];
+            for (m, x) in masses.iter().zip(positions.iter()) {
+                expansion.monomials(&[x[0] - z[0], x[1] - z[1], x[2] - z[2]], &mut monomials);
+                for (multipole, monomial) in multipoles.iter_mut().zip(monomials.iter()) {
+                    *multipole += m * monomial;
+                }
+            }
+            multipoles
+        };
+        let (z1, z2) = ([0.05, 0.02, -0.04], [0.3, -0.2, 0.1]);
+        let mut shifted = vec![0f64; expansion.len()];
+        expansion.shift_multipoles(
+            &mut shifted,
+            &multipoles(&z1),
+            &[z1[0] - z2[0], z1[1] - z2[1], z1[2] - z2[2]],
+        );
+        for (a, b) in shifted.iter().zip(multipoles(&z2).iter()) {
+            assert!((a - b).abs() < 1e-12, "{} != {}", a, b);
+        }
+
+        //far from the masses, the expansion gives the potential of the direct sum
+        let x = [4., -3., 2.5];
+        let direct: f64 = masses
+            .iter()
+            .zip(positions.iter())
+            .map(|(m, p)| -m / (0..3).map(|i| (x[i] - p[i]).powi(2)).sum::<f64>().sqrt())
+            .sum();
+        let r = [x[0] - z2[0], x[1] - z2[1], x[2] - z2[2]];
+        let phi = multipole_potential(&expansion, &shifted, &r);
+        assert!(
+            (phi - direct).abs() < 1e-8 * direct.abs(),
+            "{} != {}",
+            phi,
+            direct
+        );
+    }
+
+    #[test]
+    fn conserves_the_momentum() {
+        let tree = plummer_tree(Solver::Fmm(4), 1, 0.5);
+        let mut momentum = [0f64; 3];
+        let mut scale = 0f64;
+        for p in tree.particules.iter() {
+            for i in 0..3 {
+                momentum[i] += p.mass * p.acceleration[i];
+            }
+            scale += p.mass * p.acceleration.iter().map(|a| a * a).sum::<f64>().sqrt();
+        }
+        for i in 0..3 {
+            assert!(momentum[i].abs() < 1e-10 * scale, "{:?}", momentum);
+        }
+    }
+
+    #[test]
+    fn agrees_with_the_direct_summation() {
+        //(order, mean and max relative errors), the errors decrease with the order
+        for &(order, mean_error, max_error) in
+            [(2, 3e-2, 0.3), (4, 1e-3, 2e-2), (6, 1e-4, 2e-3)].iter()
+        {
+            let tree = plummer_tree(Solver::Fmm(order), 1, 0.5);
+            let exact = direct_acceleration(
+                &tree.particules,
+                tree.kernel,
+                tree.epsilon,
+                &tree.softenings,
+            );
+            let errors: Vec<f64> = tree
+                .particules
+                .iter()
+                .zip(exact.iter())
+                .map(|(p, ap)| {
+                    let da = (0..3)
+                        .map(|i| (p.acceleration[i] - ap[i]).powi(2))
+                        .sum::<f64>()
+                        .sqrt();
+                    da / (0..3).map(|i| ap[i] * ap[i]).sum::<f64>().sqrt()
+                })
+                .collect();
+            let mean = errors.iter().sum::<f64>() / errors.len() as f64;
+            let max = errors.iter().cloned().fold(0f64, f64::max);
+            assert!(
+                mean < mean_error && max < max_error,
+                "order {} : mean {} max {}",
+                order,
+                mean,
+                max
+            );
+        }
+    }
+}
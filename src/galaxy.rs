@@ -0,0 +1,369 @@
+use rand_distr::StandardNormal;
+
+use crate::particules::*;
+use crate::rand::Rng;
+use crate::streams::*;
+
+//spherical components are truncated at this number of scale radii
+const TRUNCATION: f64 = 10.;
+
+//Hernquist sphere (used for the bulge and the halo)
+//rho(r) = M a / (2 pi r (r + a)^3)
+#[derive(Debug, Copy, Clone)]
+pub struct Hernquist {
+    pub mass: f64,
+    pub scale: f64,
+}
+
+impl Hernquist {
+    pub fn density(&self, r: f64) -> f64 {
+        self.mass * self.scale / (2. * std::f64::consts::PI * r * (r + self.scale).powf(3.))
+    }
+
+    pub fn mass(&self, r: f64) -> f64 {
+        self.mass * r * r / ((r + self.scale) * (r + self.scale))
+    }
+
+    //mass inside r of the sphere truncated at TRUNCATION scale radii (the live components)
+    pub fn truncated_mass(&self, r: f64) -> f64 {
+        self.mass(f64::min(r, TRUNCATION * self.scale))
+    }
+
+    pub fn potential(&self, r: f64) -> f64 {
+        -self.mass / (r + self.scale)
+    }
+
+    //acceleration at the position x, the sphere being centered on the origin
+    pub fn acceleration(&self, x: &[f64; 3]) -> [f64; 3] {
+        let r = (x[0] * x[0] + x[1] * x[1] + x[2] * x[2]).sqrt();
+        if r == 0. {
+            return [0f64; 3];
+        }
+        let f = -self.mass / ((r + self.scale) * (r + self.scale) * r);
+        [f * x[0], f * x[1], f * x[2]]
+    }
+
+    //draw a radius from the truncated cumulative mass M(r) / M = r^2 / (r + a)^2
+    fn sample_radius<R: Rng>(&self, rng: &mut R) -> f64 {
+        loop {
+            let s = rng.gen_range(0f64, 1f64).sqrt();
+            let r = self.scale * s / (1. - s);
+            if r < TRUNCATION * self.scale {
+                return r;
+            }
+        }
+    }
+}
+
+//parameters of a disk galaxy: exponential disk + Hernquist bulge + Hernquist halo
+#[derive(Debug, Copy, Clone)]
+pub struct Galaxy {
+    pub disk_mass: f64,
+    //scale length R_d of the surface density exp(-R / R_d)
+    pub disk_scale: f64,
+    //scale height z0 of the vertical profile sech^2(z / z0)
+    pub disk_height: f64,
+    //Toomre parameter of the disk
+    pub toomre_q: f64,
+    //no bulge if the mass is 0
+    pub bulge: Hernquist,
+    //no halo if the mass is 0
+    pub halo: Hernquist,
+    //true -> the halo is made of particules ; false -> analytic halo potential
+    pub halo_live: bool,
+}
+
+//modified Bessel functions, polynomial approximations from Abramowitz & Stegun 9.8
+fn bessel_i0(x: f64) -> f64 {
+    if x < 3.75 {
+        let t = (x / 3.75) * (x / 3.75);
+        1. + t
+            * (3.5156229
+                + t * (3.0899424
+                    + t * (1.2067492 + t * (0.2659732 + t * (0.0360768 + t * 0.0045813)))))
+    } else {
+        let t = 3.75 / x;
+        x.exp() / x.sqrt()
+            * (0.39894228
+                + t * (0.01328592
+                    + t * (0.00225319
+                        + t * (-0.00157565
+                            + t * (0.00916281
+                                + t * (-0.02057706
+                                    + t * (0.02635537 + t * (-0.01647633 + t * 0.00392377))))))))
+    }
+}
+
+fn bessel_i1(x: f64) -> f64 {
+    if x < 3.75 {
+        let t = (x / 3.75) * (x / 3.75);
+        x * (0.5
+            + t * (0.87890594
+                + t * (0.51498869
+                    + t * (0.15084934 + t * (0.02658733 + t * (0.00301532 + t * 0.00032411))))))
+    } else {
+        let t = 3.75 / x;
+        x.exp() / x.sqrt()
+            * (0.39894228
+                + t * (-0.03988024
+                    + t * (-0.00362018
+                        + t * (0.00163801
+                            + t * (-0.01031555
+                                + t * (0.02282967
+                                    + t * (-0.02895312 + t * (0.01787654 - t * 0.00420059))))))))
+    }
+}
+
+fn bessel_k0(x: f64) -> f64 {
+    if x <= 2. {
+        let t = x * x / 4.;
+        -(x / 2.).ln() * bessel_i0(x)
+            + (-0.57721566
+                + t * (0.42278420
+                    + t * (0.23069756
+                        + t * (0.03488590 + t * (0.00262698 + t * (0.00010750 + t * 0.0000074))))))
+    } else {
+        let t = 2. / x;
+        (-x).exp() / x.sqrt()
+            * (1.25331414
+                + t * (-0.07832358
+                    + t * (0.02189568
+                        + t * (-0.01062446
+                            + t * (0.00587872 + t * (-0.00251540 + t * 0.00053208))))))
+    }
+}
+
+fn bessel_k1(x: f64) -> f64 {
+    if x <= 2. {
+        let t = x * x / 4.;
+        (x / 2.).ln() * bessel_i1(x)
+            + 1. / x
+                * (1.
+                    + t * (0.15443144
+                        + t * (-0.67278579
+                            + t * (-0.18156897
+                                + t * (-0.01919402 + t * (-0.00110404 - t * 0.00004686))))))
+    } else {
+        let t = 2. / x;
+        (-x).exp() / x.sqrt()
+            * (1.25331414
+                + t * (0.23498619
+                    + t * (-0.03655620
+                        + t * (0.01504268
+                            + t * (-0.00780353 + t * (0.00325614 - t * 0.00068245))))))
+    }
+}
+
+impl Galaxy {
+    //surface density of the disk
+    fn surface_density(&self, r: f64) -> f64 {
+        self.disk_mass / (2. * std::f64::consts::PI * self.disk_scale * self.disk_scale)
+            * (-r / self.disk_scale).exp()
+    }
+
+    //mass of the halo inside r, truncated if it is made of particules
+    //(the analytic halo is not truncated)
+    fn halo_mass(&self, r: f64) -> f64 {
+        if self.halo_live {
+            self.halo.truncated_mass(r)
+        } else {
+            self.halo.mass(r)
+        }
+    }
+
+    //derivative of f at r > 0 with the step h = 1e-4 (r + R_d)
+    //one-sided near the center, so f is never evaluated at a negative radius
+    fn derivative<F: Fn(f64) -> f64>(&self, f: F, r: f64) -> f64 {
+        let h = 1e-4 * (r + self.disk_scale);
+        if r > h {
+            (f(r + h) - f(r - h)) / (2. * h)
+        } else {
+            (f(r + h) - f(r)) / h
+        }
+    }
+
+    //square of the circular velocity in the plane of the disk
+    //the disk is the razor thin exponential disk (Freeman 1970)
+    fn circular_velocity2(&self, r: f64) -> f64 {
+        let mut v2 = self.bulge.truncated_mass(r) / r + self.halo_mass(r) / r;
+        if self.disk_mass > 0. {
+            let y = 0.5 * r / self.disk_scale;
+            v2 += 4.
+                * std::f64::consts::PI
+                * self.surface_density(0.)
+                * self.disk_scale
+                * y
+                * y
+                * (bessel_i0(y) * bessel_k0(y) - bessel_i1(y) * bessel_k1(y));
+        }
+        v2
+    }
+
+    //epicyclic frequency kappa^2 = dv_c^2/dR / R + 2 v_c^2 / R^2
+    fn kappa2(&self, r: f64) -> f64 {
+        let dv2 = self.derivative(|x| self.circular_velocity2(x), r);
+        dv2 / r + 2. * self.circular_velocity2(r) / (r * r)
+    }
+
+    //radial velocity dispersion of the disk, from the Toomre parameter
+    //Q = sigma_R kappa / (3.36 G Sigma)
+    fn sigma_r2(&self, r: f64) -> f64 {
+        let s = self.toomre_q * 3.36 * self.surface_density(r) / self.kappa2(r).sqrt();
+        s * s
+    }
+
+    //mass enclosed in a sphere of radius r, the disk being spherically averaged
+    fn enclosed_mass(&self, r: f64) -> f64 {
+        let x = r / self.disk_scale;
+        self.disk_mass * (1. - (1. + x) * (-x).exp())
+            + self.bulge.truncated_mass(r)
+            + self.halo_mass(r)
+    }
+
+    //isotropic velocity dispersion of a spherical component in the total potential,
+    //from the Jeans equation: rho sigma^2(r) = int_r^inf rho G M(r') / r'^2 dr'
+    //(the density of the component is 0 beyond its truncation)
+    fn jeans_dispersion(&self, component: &Hernquist, r: f64) -> f64 {
+        let r_max = TRUNCATION * component.scale;
+        if r >= r_max {
+            return 0.;
+        }
+        //integration on a logarithmic grid with the trapezoidal rule
+        let n = 400;
+        let (l_min, l_max) = (r.ln(), r_max.ln());
+        let h = (l_max - l_min) / n as f64;
+        let f = |l: f64| {
+            let x = l.exp();
+            component.density(x) * self.enclosed_mass(x) / (x * x) * x
+        };
+        let mut integral = 0.5 * (f(l_min) + f(l_max));
+        for i in 1..n {
+            integral += f(l_min + i as f64 * h);
+        }
+        (integral * h / component.density(r)).sqrt()
+    }
+
+    //generate a spherical component in equilibrium in the total potential
+    fn spherical_gen(
+        &self,
+        component: &Hernquist,
+        nb: usize,
+        mass: f64,
+        tag: Component,
+        seed: u64,
+    ) -> Vec<Particule> {
+        par_generation(nb, seed, |rng, _| {
+            let r = component.sample_radius(rng);
+            let x1: f64 = rng.gen_range(0f64, 1f64);
+            let x2: f64 = rng.gen_range(0f64, 1f64);
+            let z = (1. - 2. * x1) * r;
+            let x = (r * r - z * z).sqrt() * (2. * std::f64::consts::PI * x2).cos();
+            let y = (r * r - z * z).sqrt() * (2. * std::f64::consts::PI * x2).sin();
+
+            let sigma = self.jeans_dispersion(component, r);
+            let v_x: f64 = rng.sample(StandardNormal);
+            let v_y: f64 = rng.sample(StandardNormal);
+            let v_z: f64 = rng.sample(StandardNormal);
+
+            Particule {
+                position: [x, y, z],
+                speed: [sigma * v_x, sigma * v_y, sigma * v_z],
+                acceleration: [0., 0., 0.],
+                cinetic: 0f64,
+                potential: 0f64,
+                mass: mass,
+                component: tag,
+            }
+        })
+    }
+
+    //generate the exponential disk, rotating around the z axis
+    //velocities are given by the epicyclic approximation
+    fn disk_gen(&self, nb: usize, mass: f64, seed: u64) -> Vec<Particule> {
+        let r_d = self.disk_scale;
+        par_generation(nb, seed, |rng, _| {
+            //R follows R exp(-R / R_d), a gamma distribution of shape 2
+            let mut r;
+            loop {
+                let x1: f64 = rng.gen_range(0f64, 1f64);
+                let x2: f64 = rng.gen_range(0f64, 1f64);
+                r = -r_d * (x1 * x2).ln();
+                if r < TRUNCATION * r_d && r > 0. {
+                    break;
+                }
+            }
+            let phi = 2. * std::f64::consts::PI * rng.gen_range(0f64, 1f64);
+            //inverse of the cumulative distribution of sech^2
+            let z = self.disk_height * (2. * rng.gen_range(0f64, 1f64) - 1.).atanh();
+
+            let v_c2 = self.circular_velocity2(r);
+            let omega2 = v_c2 / (r * r);
+            let kappa2 = self.kappa2(r);
+            //vertical equilibrium of an isothermal sheet
+            let sigma_z2 = std::f64::consts::PI * self.surface_density(r) * self.disk_height;
+            let sigma_r2 = self.sigma_r2(r);
+            let sigma_phi2 = sigma_r2 * kappa2 / (4. * omega2);
+            //asymmetric drift
+            let dsigma_r2 = self.derivative(|x| self.sigma_r2(x), r);
+            let v_phi2 = v_c2 + sigma_r2 - sigma_phi2 - sigma_r2 * r / r_d + r * dsigma_r2;
+            let v_phi_mean = f64::max(v_phi2, 0.).sqrt();
+
+            let g1: f64 = rng.sample(StandardNormal);
+            let g2: f64 = rng.sample(StandardNormal);
+            let g3: f64 = rng.sample(StandardNormal);
+            let v_r = sigma_r2.sqrt() * g1;
+            let v_phi = v_phi_mean + sigma_phi2.sqrt() * g2;
+            let v_z = sigma_z2.sqrt() * g3;
+
+            let (sin, cos) = phi.sin_cos();
+            Particule {
+                position: [r * cos, r * sin, z],
+                speed: [v_r * cos - v_phi * sin, v_r * sin + v_phi * cos, v_z],
+                acceleration: [0., 0., 0.],
+                cinetic: 0f64,
+                potential: 0f64,
+                mass: mass,
+                component: Component::Disk,
+            }
+        })
+    }
+}
+
+//generate nb particules for a disk galaxy, all particules have the same mass
+//the particules are split between the live components in proportion to their masses
+//(the masses of the bulge and the live halo inside their truncation)
+//each component has its own random stream
+pub fn galaxy_gen(nb: usize, galaxy: &Galaxy, seed: u64) -> Vec<Particule> {
+    let bulge_mass = galaxy.bulge.truncated_mass(f64::INFINITY);
+    let halo_mass = if galaxy.halo_live {
+        galaxy.halo.truncated_mass(f64::INFINITY)
+    } else {
+        0.
+    };
+    let live_mass = galaxy.disk_mass + bulge_mass + halo_mass;
+    let mass = live_mass / nb as f64;
+    let nb_bulge = (nb as f64 * bulge_mass / live_mass).round() as usize;
+    let nb_halo = (nb as f64 * halo_mass / live_mass).round() as usize;
+    let nb_disk = nb - nb_bulge - nb_halo;
+    println!(
+        "Disk galaxy : {} disk, {} bulge and {} halo particules",
+        nb_disk, nb_bulge, nb_halo
+    );
+
+    let mut particules = galaxy.disk_gen(nb_disk, mass, seed);
+    particules.append(&mut galaxy.spherical_gen(
+        &galaxy.bulge,
+        nb_bulge,
+        mass,
+        Component::Bulge,
+        stream_seed(seed, STREAM_BULGE),
+    ));
+    particules.append(&mut galaxy.spherical_gen(
+        &galaxy.halo,
+        nb_halo,
+        mass,
+        Component::Halo,
+        stream_seed(seed, STREAM_HALO),
+    ));
+    particules
+}
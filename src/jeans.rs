@@ -0,0 +1,319 @@
+use rand_distr::StandardNormal;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::particules::*;
+use crate::profiles::*;
+use crate::rand::Rng;
+use crate::streams::*;
+use crate::tree::*;
+
+//number of points of the radial grid
+const NB_POINTS: usize = 2000;
+
+//density models for the Jeans builder
+#[derive(Debug, Clone)]
+pub enum Density {
+    //scale radius 1
+    Plummer,
+    //scale radius 1, truncated at 100 scale radii
+    Hernquist,
+    //density of a polytrope or of a truncated isothermal sphere
+    Isotropic(DistributionFunction),
+    //table of (r, rho), sorted by radius
+    Tabulated(Vec<(f64, f64)>),
+}
+
+//velocity anisotropy beta(r) = 1 - sigma_t^2 / sigma_r^2
+#[derive(Debug, Copy, Clone)]
+pub enum Anisotropy {
+    Constant(f64),
+    //Osipkov-Merritt: beta(r) = r^2 / (r^2 + r_a^2)
+    OsipkovMerritt(f64),
+}
+
+impl Anisotropy {
+    fn beta(&self, r: f64) -> f64 {
+        match self {
+            Anisotropy::Constant(beta) => *beta,
+            Anisotropy::OsipkovMerritt(r_a) => r * r / (r * r + r_a * r_a),
+        }
+    }
+}
+
+//parameters of the Jeans builder
+#[derive(Debug, Clone)]
+pub struct Jeans {
+    pub density: Density,
+    pub anisotropy: Anisotropy,
+    //true -> the speeds are drawn again if they are above the local escape speed
+    pub truncate: bool,
+}
+
+//radial grid of the Jeans model
+struct Grid {
+    radii: Vec<f64>,
+    density: Vec<f64>,
+    mass: Vec<f64>,
+    //relative potential (-potential)
+    psi: Vec<f64>,
+    sigma_r2: Vec<f64>,
+    sigma_t2: Vec<f64>,
+}
+
+impl Density {
+    //density function and the radial range used for the grid
+    fn grid_density(&self) -> (Box<dyn Fn(f64) -> f64 + '_>, f64, f64) {
+        match self {
+            Density::Plummer => (
+                Box::new(|r: f64| 3. / (4. * std::f64::consts::PI) * (1. + r * r).powf(-2.5)),
+                1e-4,
+                100.,
+            ),
+            Density::Hernquist => (
+                Box::new(|r: f64| 1. / (2. * std::f64::consts::PI * r * (1. + r).powf(3.))),
+                1e-4,
+                100.,
+            ),
+            Density::Isotropic(df) => {
+                let profile = Profile::from_df(df);
+                let r_max = *profile.radii.last().unwrap();
+                let df = *df;
+                (
+                    Box::new(move |r: f64| df.density(profile.psi_at(r))),
+                    1e-4 * r_max,
+                    r_max,
+                )
+            }
+            Density::Tabulated(table) => {
+                let r_min = table[0].0;
+                let r_max = table.last().unwrap().0;
+                (
+                    Box::new(move |r: f64| {
+                        //linear interpolation, constant density inside the first radius
+                        let i = table
+                            .iter()
+                            .position(|(x, _)| *x >= r)
+                            .unwrap_or(table.len() - 1);
+                        if i == 0 {
+                            return table[0].1;
+                        }
+                        let (r0, rho0) = table[i - 1];
+                        let (r1, rho1) = table[i];
+                        rho0 + (rho1 - rho0) * (r - r0) / (r1 - r0)
+                    }),
+                    1e-2 * r_min,
+                    r_max,
+                )
+            }
+        }
+    }
+}
+
+impl Jeans {
+    //solve the spherical Jeans equation
+    //d(rho sigma_r^2)/dr + 2 beta rho sigma_r^2 / r = -rho G M(r) / r^2
+    //with rho sigma_r^2 = 0 at the edge of the model
+    //the solution is rho sigma_r^2(r) = 1/g(r) int_r^r_max g rho G M / r'^2 dr'
+    //with ln(g) = int 2 beta / r dr
+    fn solve(&self) -> Grid {
+        let (density, r_min, r_max) = self.density.grid_density();
+        let h = (r_max / r_min).ln() / (NB_POINTS - 1) as f64;
+        let radii: Vec<f64> = (0..NB_POINTS)
+            .map(|i| r_min * (h * i as f64).exp())
+            .collect();
+        let rho: Vec<f64> = radii.iter().map(|r| density(*r)).collect();
+
+        //enclosed mass, the density being constant inside r_min
+        let mut mass = vec![4. / 3. * std::f64::consts::PI * r_min.powf(3.) * rho[0]; NB_POINTS];
+        for i in 1..NB_POINTS {
+            let dm = |j: usize| 4. * std::f64::consts::PI * radii[j].powf(3.) * rho[j];
+            mass[i] = mass[i - 1] + 0.5 * h * (dm(i - 1) + dm(i));
+        }
+        let total_mass = mass[NB_POINTS - 1];
+
+        //relative potential, from the outside
+        let mut psi = vec![total_mass / r_max; NB_POINTS];
+        for i in (0..NB_POINTS - 1).rev() {
+            let f = |j: usize| mass[j] / radii[j];
+            psi[i] = psi[i + 1] + 0.5 * h * (f(i) + f(i + 1));
+        }
+
+        let mut ln_g = vec![0f64; NB_POINTS];
+        for i in 1..NB_POINTS {
+            let beta = |j: usize| self.anisotropy.beta(radii[j]);
+            ln_g[i] = ln_g[i - 1] + h * (beta(i - 1) + beta(i));
+        }
+
+        //pressure rho sigma_r^2, integrated from the outside
+        let mut pressure = vec![0f64; NB_POINTS];
+        for i in (0..NB_POINTS - 1).rev() {
+            let f = |j: usize| (ln_g[j] - ln_g[i]).exp() * rho[j] * mass[j] / radii[j];
+            pressure[i] =
+                pressure[i + 1] * (ln_g[i + 1] - ln_g[i]).exp() + 0.5 * h * (f(i) + f(i + 1));
+        }
+
+        let sigma_r2: Vec<f64> = (0..NB_POINTS)
+            .map(|i| {
+                if rho[i] > 0. {
+                    f64::max(pressure[i] / rho[i], 0.)
+                } else {
+                    0.
+                }
+            })
+            .collect();
+        let sigma_t2: Vec<f64> = (0..NB_POINTS)
+            .map(|i| (1. - self.anisotropy.beta(radii[i])) * sigma_r2[i])
+            .collect();
+
+        Grid {
+            radii: radii,
+            density: rho,
+            mass: mass,
+            psi: psi,
+            sigma_r2: sigma_r2,
+            sigma_t2: sigma_t2,
+        }
+    }
+}
+
+impl Grid {
+    fn index(&self, r: f64) -> usize {
+        match self.radii.binary_search_by(|x| x.partial_cmp(&r).unwrap()) {
+            Ok(i) => i,
+            Err(i) => usize::min(i, self.radii.len() - 1),
+        }
+    }
+
+    //potential energy -int G M / r dM and kinetic energy int rho (sigma_r^2 + 2 sigma_t^2) / 2 dV
+    fn energies(&self) -> (f64, f64) {
+        let mut w = 0.;
+        let mut t = 0.;
+        for i in 1..self.radii.len() {
+            let dm = self.mass[i] - self.mass[i - 1];
+            w -= 0.5 * (self.mass[i] / self.radii[i] + self.mass[i - 1] / self.radii[i - 1]) * dm;
+            t += 0.25
+                * (self.sigma_r2[i]
+                    + 2. * self.sigma_t2[i]
+                    + self.sigma_r2[i - 1]
+                    + 2. * self.sigma_t2[i - 1])
+                * dm;
+        }
+        (w, t)
+    }
+}
+
+//generate nb particules with the velocity dispersions given by the Jeans equation
+//the speeds are gaussian, the particules are in N-body units
+//(total mass 1, potential energy -1/2, G = 1)
+pub fn jeans_gen(nb: usize, jeans: &Jeans, seed: u64) -> Vec<Particule> {
+    let grid = jeans.solve();
+    let total_mass = *grid.mass.last().unwrap();
+    let (w, t) = grid.energies();
+    println!("Jeans model : virial ratio of the model {}", 2. * t / w);
+    //factors to N-body units
+    let length_factor = -2. * w / (total_mass * total_mass);
+    let speed_factor = (1. / (total_mass * length_factor)).sqrt();
+
+    let nb_redraw = AtomicUsize::new(0);
+    let particules = par_generation(nb, seed, |rng, _| {
+        //radius from the enclosed mass
+        let m = rng.gen_range(0., total_mass);
+        let i = match grid.mass.binary_search_by(|x| x.partial_cmp(&m).unwrap()) {
+            Ok(i) => i,
+            Err(i) => usize::max(i, 1),
+        };
+        let r = if m <= grid.mass[0] {
+            grid.radii[0] * (m / grid.mass[0]).powf(1. / 3.)
+        } else {
+            let w = (m - grid.mass[i - 1]) / (grid.mass[i] - grid.mass[i - 1]);
+            grid.radii[i - 1] + w * (grid.radii[i] - grid.radii[i - 1])
+        };
+        let j = grid.index(r);
+        let sigma_r = grid.sigma_r2[j].sqrt();
+        let sigma_t = grid.sigma_t2[j].sqrt();
+        let v_esc2 = 2. * grid.psi[j];
+
+        //radial and tangential components
+        let (mut v_r, mut v_t1, mut v_t2): (f64, f64, f64);
+        loop {
+            v_r = sigma_r * rng.sample::<f64, _>(StandardNormal);
+            v_t1 = sigma_t * rng.sample::<f64, _>(StandardNormal);
+            v_t2 = sigma_t * rng.sample::<f64, _>(StandardNormal);
+            if !jeans.truncate || v_r * v_r + v_t1 * v_t1 + v_t2 * v_t2 < v_esc2 {
+                break;
+            }
+            nb_redraw.fetch_add(1, Ordering::Relaxed);
+        }
+
+        //local basis (e_r, e_t1, e_t2)
+        let e_r = random_direction(rng, 1.);
+        let e_t1 = if e_r[0].abs() < 0.9 {
+            [0., e_r[2], -e_r[1]]
+        } else {
+            [-e_r[2], 0., e_r[0]]
+        };
+        let norm = (e_t1[0] * e_t1[0] + e_t1[1] * e_t1[1] + e_t1[2] * e_t1[2]).sqrt();
+        let e_t1 = [e_t1[0] / norm, e_t1[1] / norm, e_t1[2] / norm];
+        let e_t2 = [
+            e_r[1] * e_t1[2] - e_r[2] * e_t1[1],
+            e_r[2] * e_t1[0] - e_r[0] * e_t1[2],
+            e_r[0] * e_t1[1] - e_r[1] * e_t1[0],
+        ];
+
+        let mut position = [0f64; 3];
+        let mut speed = [0f64; 3];
+        for k in 0..3 {
+            position[k] = length_factor * r * e_r[k];
+            speed[k] = speed_factor * (v_r * e_r[k] + v_t1 * e_t1[k] + v_t2 * e_t2[k]);
+        }
+        Particule {
+            position: position,
+            speed: speed,
+            acceleration: [0., 0., 0.],
+            cinetic: 0f64,
+            potential: 0f64,
+            mass: 1. / (nb as f64),
+            component: Component::Cluster,
+        }
+    });
+    if jeans.truncate {
+        println!(
+            "Jeans model : {} speeds above the escape speed drawn again",
+            nb_redraw.into_inner()
+        );
+    }
+    particules
+}
+
+//short run to check the equilibrium of the initial conditions
+//the particules are evolved during duration dynamical times,
+//the virial ratio and R50 are reported, then the initial conditions are restored
+pub fn jeans_check(tree: &mut Tree, duration: f64) {
+    let state = tree.save();
+    let (virial_init, r50_init) = (tree.virial, tree.rayons[1]);
+    let mut t = 0.;
+    let mut virial_max = 0f64;
+    while t < duration {
+        for _ in 0..10 {
+            tree.leap_frog();
+            t += tree.dt / tree.dynamical_time;
+        }
+        tree.compute_center();
+        tree.compute_rayons();
+        tree.compute_energy();
+        tree.compute_dt();
+        virial_max = f64::max(virial_max, (tree.virial + 1.).abs());
+        println!(
+            "Jeans check : t {} virial {} R50 {}",
+            t, tree.virial, tree.rayons[1]
+        );
+    }
+    println!(
+        "Jeans check : initial virial {} (|2T/W + 1| = {}), max |2T/W + 1| {}, R50 changed by {}",
+        virial_init,
+        (virial_init + 1.).abs(),
+        virial_max,
+        tree.rayons[1] / r50_init - 1.
+    );
+    tree.restore(state);
+}
@@ -4,21 +4,68 @@ extern crate rand;
 extern crate rayon;
 extern crate std;
 extern crate csv;
+use crate::ini::ini::Properties;
 use crate::ini::Ini;
 use crate::std::env::args;
 use crate::std::fs;
+use crate::std::str::FromStr;
 
+mod binaries;
+mod container;
+mod direct;
+mod fmm;
+mod galaxy;
+mod jeans;
+mod multipoles;
 mod particules;
+mod perturbation;
+mod planetary;
+mod pm;
+mod profiles;
+mod resampling;
+mod scaling;
+mod scf;
+mod shells;
+mod softening;
+mod streams;
 mod tree;
 mod write;
+use crate::binaries::*;
+use crate::direct::*;
+use crate::fmm::*;
+use crate::galaxy::*;
+use crate::jeans::*;
+use crate::particules::*;
+use crate::perturbation::*;
+use crate::planetary::*;
+use crate::profiles::*;
+use crate::resampling::*;
+use crate::scaling::*;
+use crate::scf::*;
+use crate::shells::*;
+use crate::softening::*;
 use crate::tree::*;
 use crate::write::*;
 
-fn simulation(tree: &mut Tree, time: f64, folder: String, crash_time: f64) {
+fn simulation(
+    tree: &mut Tree,
+    time: f64,
+    folder: String,
+    crash_time: f64,
+    force_checks: &[f64],
+    force_check_size: usize,
+) {
     //create folders
     let _ = fs::create_dir(folder.clone());
     let _ = fs::create_dir(format!("{}/positions", folder));
     let _ = fs::create_dir(format!("{}/densities", folder));
+    if let Solver::Scf(_) = tree.solver {
+        let _ = fs::create_dir(format!("{}/scf", folder));
+    }
+    if !tree.binaries.is_empty() {
+        let _ = fs::create_dir(format!("{}/binaries", folder));
+        write_binaries_catalogue(tree, format!("{}/binaries.csv", folder));
+    }
 
     //time
     let mut t = 0f64;
@@ -26,6 +73,22 @@ fn simulation(tree: &mut Tree, time: f64, folder: String, crash_time: f64) {
     let mut infos = Vec::new();
     //vector for inertia matrix
     let mut inertia_matrices = Vec::new();
+    //crossings, pressure and virial ratio with the wall of the container
+    let mut walls = Vec::new();
+    if let Some(container) = &tree.container {
+        println!("container : radius {}", container.radius);
+    }
+    //log of the removed particules (t, escaper), starting with the initial conditions
+    let mut escapers: Vec<(f64, Escaper)> = tree.escapers.drain(..).map(|e| (0., e)).collect();
+    //errors of the accelerations at the times of force_checks
+    let mut forces = Vec::new();
+    let mut check = 0;
+    //log of the changes of epsilon: t, old and new epsilon, energy jump
+    let mut softenings = Vec::new();
+    //vector for the projections on the mode of the perturbation
+    let mut modes = Vec::new();
+    //vector for the errors of the forces compared to the exact shells
+    let mut errors_shells = Vec::new();
     //count files
     let mut c = 0;
     //we save the general value of mu (dt = dynamical_time / mu)
@@ -33,6 +96,8 @@ fn simulation(tree: &mut Tree, time: f64, folder: String, crash_time: f64) {
     //and theta
     let theta = tree.theta;
 
+    println!("scaling : {:?}", tree.scaling);
+
     // Added to the original code
     // write_velocities(&tree, format!("{}/initial_velocities.csv", folder));
 
@@ -46,19 +111,54 @@ fn simulation(tree: &mut Tree, time: f64, folder: String, crash_time: f64) {
             tree.theta = theta;
         }
 
+        //the drift is printed, so it is computed first
+        tree.compute_drift();
+
         println!("***");
         println!("t : {}", t);
         println!(" epsilon : {:?}", tree.epsilon);
         println!(" virial : {:?}", tree.virial);
         println!(" energy : {:?}", tree.energy);
+        println!(" drift : {:?}", tree.drift);
+        if tree.nb_teleported > 0 {
+            println!(" teleported : {}", tree.nb_teleported);
+        }
+        if tree.removed_mass > 0. {
+            println!(
+                " removed : mass {} energy {} ({} particules left)",
+                tree.removed_mass,
+                tree.removed_energy,
+                tree.particules.len()
+            );
+        }
 
         //compute new values
         tree.compute_center();
         tree.compute_rayons();
         tree.compute_inertia_matrix();
+        tree.compute_wall_pressure();
         tree.compute_energy();
-        tree.compute_epsilon();
+        let epsilon = tree.epsilon;
+        if let Some(jump) = tree.update_epsilon(t) {
+            println!(
+                " epsilon changed : {} -> {}, energy jump {}",
+                epsilon, tree.epsilon, jump
+            );
+            softenings.push(vec![t, epsilon, tree.epsilon, jump]);
+        }
         tree.compute_dt();
+        //comparison of the accelerations to the exact ones, at the chosen times
+        if check < force_checks.len() && t >= force_checks[check] {
+            let (errors, interactions) = force_errors(tree, force_check_size);
+            println!(
+                " force errors : {:?} ({} interactions per particule)",
+                errors, interactions
+            );
+            forces.push(vec![t, errors[0], errors[1], errors[2], interactions]);
+            while check < force_checks.len() && t >= force_checks[check] {
+                check += 1;
+            }
+        }
 
         //save those values in vectors
         infos.push(vec![
@@ -69,8 +169,48 @@ fn simulation(tree: &mut Tree, time: f64, folder: String, crash_time: f64) {
             tree.rayons[0],
             tree.rayons[1],
             tree.rayons[2],
+            tree.drift[0],
+            tree.drift[1],
+            tree.drift[2],
+            tree.removed_mass,
+            tree.removed_energy,
         ]);
         inertia_matrices.push(tree.inertia_matrix);
+        if let Some(container) = &tree.container {
+            println!(
+                " wall : crossings {} pressure {} virial with the wall {}",
+                container.crossings, container.pressure, tree.wall_virial
+            );
+            walls.push(vec![
+                t,
+                container.crossings as f64,
+                container.pressure,
+                tree.wall_virial,
+            ]);
+        }
+        if let Some(perturbation) = &tree.perturbation {
+            let (density, velocity) = mode_amplitudes(tree, perturbation);
+            println!(
+                " mode amplitudes : density {} velocity {}",
+                density, velocity
+            );
+            modes.push(vec![t, density, velocity]);
+        }
+        if let Some(shells) = &tree.shells {
+            let (acc_errors, pot_errors) = shells_errors(tree, shells);
+            println!(" acceleration errors : {:?}", acc_errors);
+            println!(" potential errors : {:?}", pot_errors);
+            let mut errors = vec![t];
+            errors.extend_from_slice(&acc_errors);
+            errors.extend_from_slice(&pot_errors);
+            if let Some((acc_errors, pot_errors)) = cavity_errors(tree, shells) {
+                println!(" cavity acceleration errors : {:?}", acc_errors);
+                println!(" cavity potential errors : {:?}", pot_errors);
+                errors.extend_from_slice(&acc_errors);
+                errors.extend_from_slice(&pot_errors);
+            }
+            errors_shells.push(errors);
+        }
 
         //write to file the positions of the particules and the density
         write_positions(&tree, format!("{}/positions/{}.csv", folder, c.to_string()));
@@ -78,6 +218,12 @@ fn simulation(tree: &mut Tree, time: f64, folder: String, crash_time: f64) {
             &tree,
             format!("{}/densities/{}.csv", folder.clone(), t.to_string()),
         );
+        if !tree.binaries.is_empty() {
+            write_binaries(tree, format!("{}/binaries/{}.csv", folder, c));
+        }
+        if let Solver::Scf(scf) = &tree.solver {
+            write_scf_coefficients(tree, scf, format!("{}/scf/{}.csv", folder, c));
+        }
 
         //simulate 10 steps
         for _ in 0..10 {
@@ -85,13 +231,349 @@ fn simulation(tree: &mut Tree, time: f64, folder: String, crash_time: f64) {
             tree.leap_frog();
             //increment the current time, in dynamical time scale
             t += tree.dt / tree.dynamical_time;
+            for escaper in tree.escapers.drain(..) {
+                escapers.push((t, escaper));
+            }
         }
 
         c = c + 1;
     }
 
     //write all the values of infos and inertia_matrices to file
-    write_infos(&infos, &inertia_matrices, folder.clone());
+    write_infos(&infos, &inertia_matrices, &tree.scaling, folder.clone());
+    if tree.shells.is_some() {
+        write_shells_errors(&errors_shells, folder.clone());
+    }
+    if let Some(container) = &tree.container {
+        write_container(&walls, container.radius, folder.clone());
+    }
+    if tree.escaper_policy == EscaperPolicy::Remove {
+        write_escapers(&escapers, folder.clone());
+    }
+    if !forces.is_empty() {
+        write_force_errors(&forces, folder.clone());
+    }
+    if !softenings.is_empty() {
+        write_softenings(&softenings, folder.clone());
+    }
+    if let Some(perturbation) = &tree.perturbation {
+        write_modes(&modes, perturbation, folder.clone());
+    }
+}
+
+//read an optional value from the configuration file
+fn get_opt<T: FromStr>(section: &Properties, key: &str) -> Option<T> {
+    section.get(key).map(|value| {
+        value
+            .parse()
+            .unwrap_or_else(|_| panic!("invalid value for {} : {}", key, value))
+    })
+}
+
+//read an optional value from the configuration file, with a default value
+fn get_or<T: FromStr>(section: &Properties, key: &str, default: T) -> T {
+    get_opt(section, key).unwrap_or(default)
+}
+
+fn read_component(name: &str) -> Component {
+    match name {
+        "cluster" => Component::Cluster,
+        "disk" => Component::Disk,
+        "bulge" => Component::Bulge,
+        "halo" => Component::Halo,
+        "central" => Component::Central,
+        _ => panic!("unknown component : {}", name),
+    }
+}
+
+//read the shells of the shells model
+//shells are separated by commas, each one is given by "radius_in radius_out mass"
+fn read_shells(section: &Properties) -> Shells {
+    let shells = section
+        .get("shells")
+        .expect("the shells key is needed for the shells model");
+    Shells {
+        shells: shells
+            .split(',')
+            .map(|shell| {
+                let values: Vec<f64> = shell
+                    .split_whitespace()
+                    .map(|v| v.parse().unwrap())
+                    .collect();
+                if values.len() != 3 || values[0] > values[1] {
+                    panic!("invalid shell : {}", shell);
+                }
+                Shell {
+                    radius_in: values[0],
+                    radius_out: values[1],
+                    mass: values[2],
+                }
+            })
+            .collect(),
+    }
+}
+
+//read the number of cells of the side of the mesh, a power of 2
+fn read_grid(section: &Properties) -> usize {
+    let grid: usize = get_or(section, "pm_grid", 64);
+    if !grid.is_power_of_two() || grid < 16 {
+        panic!("pm_grid must be a power of 2, at least 16, got {}", grid);
+    }
+    grid
+}
+
+//read a list of times separated by commas, empty if the key is not set
+fn read_times(section: &Properties, key: &str) -> Vec<f64> {
+    let mut times: Vec<f64> = match section.get(key) {
+        Some(times) => times
+            .split(',')
+            .map(|t| {
+                t.trim()
+                    .parse()
+                    .unwrap_or_else(|_| panic!("invalid value for {} : {}", key, t))
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+    times.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    times
+}
+
+//read the schedule of the softening
+//points are separated by commas, each one is given by "t epsilon", t in dynamical time
+fn read_schedule(section: &Properties) -> Vec<(f64, f64)> {
+    let schedule = section
+        .get("softening_schedule")
+        .expect("the softening_schedule key is needed for the scheduled softening");
+    let schedule: Vec<(f64, f64)> = schedule
+        .split(',')
+        .map(|point| {
+            let values: Vec<f64> = point
+                .split_whitespace()
+                .map(|v| v.parse().unwrap())
+                .collect();
+            if values.len() != 2 || values[1] <= 0. {
+                panic!("invalid point of the softening schedule : {}", point);
+            }
+            (values[0], values[1])
+        })
+        .collect();
+    if schedule.windows(2).any(|w| w[0].0 >= w[1].0) {
+        panic!("the times of the softening schedule must be increasing");
+    }
+    schedule
+}
+
+//read the index of a polytrope (1.5 <= n <= 5)
+fn read_polytrope(section: &Properties) -> DistributionFunction {
+    let n = get_or(section, "polytrope_n", 3.);
+    if !(1.5..=5.).contains(&n) {
+        panic!("the polytrope index must be between 1.5 and 5, got {}", n);
+    }
+    DistributionFunction::Polytrope(n)
+}
+
+//read the central potential of a woolley model
+fn read_woolley(section: &Properties) -> DistributionFunction {
+    let w0 = get_or(section, "woolley_w0", 5.);
+    if w0 <= 0. {
+        panic!("the woolley central potential must be positive, got {}", w0);
+    }
+    DistributionFunction::Woolley(w0)
+}
+
+//read the density and the anisotropy of the jeans model
+fn read_jeans(section: &Properties) -> Jeans {
+    let density = get_or(section, "jeans_density", "plummer".to_string());
+    let density = match density.as_str() {
+        "plummer" => Density::Plummer,
+        "hernquist" => Density::Hernquist,
+        "polytrope" => Density::Isotropic(read_polytrope(section)),
+        "woolley" => Density::Isotropic(read_woolley(section)),
+        //file with one "r;rho" line per radius
+        file_name => {
+            let content = fs::read_to_string(file_name)
+                .unwrap_or_else(|_| panic!("can't read the density file : {}", file_name));
+            let mut table: Vec<(f64, f64)> = content
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(|line| {
+                    let values: Vec<f64> = line
+                        .split(';')
+                        .map(|v| {
+                            v.trim().parse().unwrap_or_else(|_| {
+                                panic!("invalid line in the density file {} : {}", file_name, line)
+                            })
+                        })
+                        .collect();
+                    if values.len() < 2 || values[0] <= 0. || values[1] < 0. {
+                        panic!(
+                            "the lines of the density file {} must be r;rho with r > 0 and rho >= 0, got : {}",
+                            file_name, line
+                        );
+                    }
+                    (values[0], values[1])
+                })
+                .collect();
+            table.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+            if table.len() < 2 {
+                panic!(
+                    "the density file {} needs at least 2 radii, got {}",
+                    file_name,
+                    table.len()
+                );
+            }
+            if table.windows(2).any(|w| w[0].0 == w[1].0) {
+                panic!("the density file {} has the same radius twice", file_name);
+            }
+            Density::Tabulated(table)
+        }
+    };
+    let anisotropy = match get_opt(section, "anisotropy_radius") {
+        Some(r_a) => Anisotropy::OsipkovMerritt(r_a),
+        None => Anisotropy::Constant(get_or(section, "anisotropy", 0.)),
+    };
+    Jeans {
+        density: density,
+        anisotropy: anisotropy,
+        truncate: get_or(section, "jeans_truncate", true),
+    }
+}
+
+//read the model of the initial conditions
+//without the model key, the plummer and from_csv keys are used
+fn read_model(section: &Properties) -> Model {
+    //is it a plummer model or a uniform sphere
+    let plummer = get_or(section, "plummer", true);
+    //should read initial distribution from a .csv file provided by stdin (<)
+    let from_csv = get_or(section, "from_csv", false);
+    let default = if from_csv {
+        "csv"
+    } else if plummer {
+        "plummer"
+    } else {
+        "uniform"
+    };
+
+    match get_or(section, "model", default.to_string()).as_str() {
+        "uniform" => Model::Uniform,
+        "plummer" => Model::Plummer,
+        "csv" => Model::Csv,
+        "disk" => Model::Galaxy(Galaxy {
+            disk_mass: get_or(section, "disk_mass", 1.),
+            disk_scale: get_or(section, "disk_scale", 1.),
+            disk_height: get_or(section, "disk_height", 0.1),
+            toomre_q: get_or(section, "toomre_q", 1.5),
+            bulge: Hernquist {
+                mass: get_or(section, "bulge_mass", 0.),
+                scale: get_or(section, "bulge_scale", 0.2),
+            },
+            halo: Hernquist {
+                mass: get_or(section, "halo_mass", 0.),
+                scale: get_or(section, "halo_scale", 5.),
+            },
+            halo_live: get_or(section, "halo_live", true),
+        }),
+        "shells" => Model::Shells(read_shells(section)),
+        "polytrope" => Model::Isotropic(read_polytrope(section)),
+        "woolley" => Model::Isotropic(read_woolley(section)),
+        "jeans" => Model::Jeans(read_jeans(section)),
+        "planetary" => Model::Planetary(Planetary {
+            central_mass: get_or(section, "central_mass", 1.),
+            disk_mass: get_or(section, "disk_mass", 1e-3),
+            a_min: get_or(section, "a_min", 1.),
+            a_max: get_or(section, "a_max", 2.),
+            a_power: get_or(section, "a_power", 1.),
+            sigma_e: get_or(section, "sigma_e", 0.01),
+            sigma_i: get_or(section, "sigma_i", 0.005),
+            mass_ratio: get_or(section, "mass_ratio", 1.),
+            mass_index: get_or(section, "mass_index", 2.),
+        }),
+        model => panic!("unknown model : {}", model),
+    }
+}
+
+//read the parameters of the primordial binaries, None if there is none
+fn read_binaries(section: &Properties) -> Option<Binaries> {
+    let fraction = get_or(section, "binary_fraction", 0.);
+    if fraction <= 0. {
+        return None;
+    }
+    let distribution = get_or(section, "binary_distribution", "loguniform".to_string());
+    let semi_major_axis = match distribution.as_str() {
+        "loguniform" => SemiMajorAxis::LogUniform,
+        "duquennoy-mayor" => SemiMajorAxis::DuquennoyMayor {
+            time_unit: get_opt(section, "binary_time_unit")
+                .expect("binary_time_unit is needed for the duquennoy-mayor distribution"),
+        },
+        _ => panic!("unknown binary distribution : {}", distribution),
+    };
+    Some(Binaries {
+        fraction: fraction,
+        semi_major_axis: semi_major_axis,
+        a_min: get_or(section, "binary_a_min", 1e-4),
+        a_max: get_or(section, "binary_a_max", 1e-2),
+    })
+}
+
+//read the resampling of the initial conditions, None if the resolution is kept
+//(nb is the number of particules before the resampling)
+fn read_resampling(section: &Properties, nb: usize) -> Option<Resampling> {
+    match (
+        get_opt::<f64>(section, "downsample"),
+        get_opt::<usize>(section, "upsample"),
+    ) {
+        (Some(_), Some(_)) => panic!("downsample and upsample can't be used together"),
+        (Some(fraction), None) => {
+            if fraction <= 0. || fraction > 1. {
+                panic!("downsample must be between 0 (excluded) and 1, got {}", fraction);
+            }
+            if (fraction * nb as f64).round() < 1. {
+                panic!(
+                    "downsample = {} keeps no particule out of {}",
+                    fraction, nb
+                );
+            }
+            Some(Resampling::Downsample(fraction))
+        }
+        (None, Some(children)) => {
+            if children < 1 {
+                panic!("upsample must be at least 1, got {}", children);
+            }
+            let jitter = get_or(section, "upsample_jitter", 0.);
+            if jitter < 0. {
+                panic!("upsample_jitter can't be negative, got {}", jitter);
+            }
+            Some(Resampling::Upsample {
+                children: children,
+                jitter: jitter,
+            })
+        }
+        (None, None) => None,
+    }
+}
+
+//read the perturbation of the initial conditions, None if there is none
+fn read_perturbation(section: &Properties) -> Option<Perturbation> {
+    let amplitude = get_opt(section, "perturbation_amplitude")?;
+    let mode = get_or(section, "perturbation_mode", "density".to_string());
+    Some(Perturbation {
+        l: get_or(section, "perturbation_l", 2),
+        m: get_or(section, "perturbation_m", 0),
+        mode: match mode.as_str() {
+            "density" => Mode::Density,
+            "velocity" => Mode::Velocity,
+            _ => panic!("unknown perturbation mode : {}", mode),
+        },
+        shape: match get_opt(section, "perturbation_radius") {
+            Some(radius) => RadialShape::Gaussian {
+                radius: radius,
+                width: get_or(section, "perturbation_width", 0.5),
+            },
+            None => RadialShape::Uniform,
+        },
+        amplitude: amplitude,
+    })
 }
 
 fn main() {
@@ -101,23 +583,36 @@ fn main() {
 
     let section = conf.section(None::<String>).unwrap();
     //number of particules
-    let nb_particules = section.get("nb_particules").unwrap().parse().unwrap();
+    let nb_particules: usize = section.get("nb_particules").unwrap().parse().unwrap();
+    //(the planetary model needs at least the central body)
+    if nb_particules < 1 {
+        panic!("nb_particules must be at least 1, got {}", nb_particules);
+    }
     //number of particles positions saved
     let nb_particules_save = section.get("nb_particules_save").unwrap().parse().unwrap();
     //dt = dynamycal_time / mu
     let mu = section.get("mu").unwrap().parse().unwrap();
     //epsilon = (4/(3*N*pi))^(1/3) R50 / lambda
     let lambda = section.get("lambda").unwrap().parse().unwrap();
+    //targets for the scaling of the initial conditions, each one is optional
     //initial value of the virial ratio
-    let virial: f64 = section.get("virial").unwrap().parse().unwrap();
+    //total energy, half mass radius, central velocity dispersion and total mass
+    let targets = Targets {
+        virial: get_opt(section, "virial"),
+        energy: get_opt(section, "energy"),
+        r50: get_opt(section, "r50"),
+        sigma_c: get_opt(section, "sigma_c"),
+        mass: get_opt(section, "total_mass"),
+    };
     //duration of the simulation in dynamical time
     let time = section.get("time").unwrap().parse().unwrap();
     //approximation of the acceleration
     let theta = section.get("theta").unwrap().parse().unwrap();
-    //is it a plummer model or a uniform sphere
-    let plummer = section.get("plummer").unwrap().parse().unwrap();
-    //should read initial distribution from a .csv file provided by stdin (<)
-    let from_csv = section.get("from_csv").unwrap().parse().unwrap();
+    //model used for the initial conditions
+    let model = read_model(section);
+    //seed of the random streams of the initial conditions, random if not set
+    let seed = get_opt(section, "seed").unwrap_or_else(rand::random::<u64>);
+    println!("seed : {}", seed);
     //number of bins used for the density
     let nb_bins = section.get("nb_bins").unwrap().parse().unwrap();
     //number of neighbors used for the local density
@@ -128,23 +623,159 @@ fn main() {
     let crash_time = section.get("crash_time").unwrap().parse().unwrap();
     let mu_init = section.get("mu_init").unwrap().parse().unwrap();
     let theta_init = section.get("theta_init").unwrap().parse().unwrap();
+    //compute the inertia matrix and the density for one component only
+    let restrict = get_opt::<String>(section, "restrict").map(|c| read_component(&c));
+    //size of the root node: bbox or a multiple of R90
+    let root_size = match get_or(section, "root_size", "bbox".to_string()).as_str() {
+        "bbox" => RootSize::BoundingBox,
+        factor => RootSize::R90(
+            factor
+                .parse()
+                .unwrap_or_else(|_| panic!("invalid value for root_size : {}", factor)),
+        ),
+    };
+    //policy for the particules outside the root node: keep, remove, reflect or teleport
+    let escaper_policy = match get_or(section, "escapers", "keep".to_string()).as_str() {
+        "keep" => EscaperPolicy::Keep,
+        "remove" => EscaperPolicy::Remove,
+        "reflect" => EscaperPolicy::Reflect,
+        "teleport" => EscaperPolicy::Teleport,
+        policy => panic!("unknown escaper policy : {}", policy),
+    };
+    //the escaper policy is applied outside the sphere of radius k * R90, there is none with bbox
+    if let RootSize::BoundingBox = root_size {
+        if escaper_policy != EscaperPolicy::Keep {
+            panic!("the escaper policy needs root_size=k (a sphere of radius k * R90), not bbox");
+        }
+    }
+    //radius of the reflecting spherical container, centered on the origin of the model
+    let container_radius = get_opt(section, "container_radius");
+    //order of the multipole expansion of the nodes: 1 monopole, 2 quadrupole, 3 octupole
+    let multipole_order = get_or(section, "multipole_order", 1);
+    if !(1..=3).contains(&multipole_order) {
+        panic!("multipole_order must be 1, 2 or 3, got {}", multipole_order);
+    }
+    //softening kernel: plummer, spline, dehnen or clamp
+    //(dehnen by default for a planetary system, so the central body is newtonian beyond its support)
+    let default_kernel = match model {
+        Model::Planetary(_) => "dehnen",
+        _ => "plummer",
+    };
+    let kernel = match get_or(section, "softening_kernel", default_kernel.to_string()).as_str() {
+        "plummer" => Kernel::Plummer,
+        "spline" => Kernel::Spline,
+        "dehnen" => Kernel::Dehnen,
+        "clamp" => Kernel::Clamp,
+        kernel => panic!("unknown softening kernel : {}", kernel),
+    };
+    //evolution of epsilon: fixed, adaptive, scheduled or individual
+    let softening = match get_or(section, "softening", "adaptive".to_string()).as_str() {
+        "fixed" => SofteningPolicy::Fixed(get_opt(section, "epsilon")),
+        "adaptive" => SofteningPolicy::Adaptive,
+        "scheduled" => SofteningPolicy::Scheduled(read_schedule(section)),
+        "individual" => SofteningPolicy::Individual,
+        policy => panic!("unknown softening policy : {}", policy),
+    };
+    //method used for the accelerations: tree, direct, fmm, pm, treepm, scf or spherical
+    let solver = match get_or(section, "solver", "tree".to_string()).as_str() {
+        "tree" => Solver::Tree,
+        "direct" => Solver::Direct,
+        "fmm" => {
+            let order = get_or(section, "fmm_order", 4);
+            if !(1..=MAX_ORDER).contains(&order) {
+                panic!(
+                    "fmm_order must be between 1 and {}, got {}",
+                    MAX_ORDER, order
+                );
+            }
+            Solver::Fmm(order)
+        }
+        "pm" => Solver::Pm(read_grid(section)),
+        "treepm" => Solver::TreePm(read_grid(section), get_or(section, "pm_split", 1.25)),
+        "scf" => Solver::Scf(Scf {
+            basis: match get_or(section, "scf_basis", "hernquist".to_string()).as_str() {
+                "hernquist" => Basis::Hernquist,
+                "plummer" => Basis::CluttonBrock,
+                basis => panic!("unknown scf basis : {}", basis),
+            },
+            nmax: get_or(section, "scf_nmax", 10),
+            lmax: get_or(section, "scf_lmax", 6),
+            scale: get_opt(section, "scf_scale"),
+        }),
+        "spherical" => Solver::Spherical,
+        solver => panic!("unknown solver : {}", solver),
+    };
+    //criterion to open the nodes: geometric, barnes-hut or relative
+    let opening = match get_or(section, "opening", "geometric".to_string()).as_str() {
+        "geometric" => Opening::Geometric,
+        "barnes-hut" => Opening::BarnesHut,
+        "relative" => Opening::Relative(get_or(section, "opening_alpha", 0.005)),
+        opening => panic!("unknown opening criterion : {}", opening),
+    };
+    //change of the number of particules of the initial conditions
+    let resampling = read_resampling(section, nb_particules);
+    //perturbation of order (l, m) of the initial conditions
+    let perturbation = read_perturbation(section);
+    if let Some(p) = &perturbation {
+        if p.m.unsigned_abs() as usize > p.l {
+            panic!("perturbation_m must be between -l and l");
+        }
+    }
+    //primordial binaries
+    let binaries = read_binaries(section);
+    //remove the center of mass position and velocity from the initial conditions
+    let barycentric = get_or(section, "barycentric", false);
+    //keep the system in the barycentric frame during the simulation
+    let keep_centered = get_or(section, "keep_centered", false);
 
     //build the octree and generate particules
-    let mut tree = Tree::new_tree(
-        nb_particules,
-        nb_particules_save,
-        mu,
-        lambda,
-        virial,
-        theta,
-        plummer,
-        from_csv,
-        nb_bins,
-        nb_neighbors,
-        mu_init,
-        theta_init,
-    );
+    let mut tree = Tree::new_tree(Parameters {
+        nb: nb_particules,
+        nb_save: nb_particules_save,
+        mu: mu,
+        lambda: lambda,
+        targets: targets,
+        theta: theta,
+        model: model,
+        seed: seed,
+        nb_bins: nb_bins,
+        nb_neighbors: nb_neighbors,
+        mu_init: mu_init,
+        theta_init: theta_init,
+        barycentric: barycentric,
+        keep_centered: keep_centered,
+        restrict: restrict,
+        root_size: root_size,
+        escaper_policy: escaper_policy,
+        container_radius: container_radius,
+        multipole_order: multipole_order,
+        opening: opening,
+        kernel: kernel,
+        softening: softening,
+        solver: solver,
+        resampling: resampling,
+        perturbation: perturbation,
+        binaries: binaries,
+    });
+
+    //short run to check the equilibrium of the jeans model
+    let jeans_check_time = get_or(section, "jeans_check", 0.);
+    if jeans_check_time > 0. {
+        jeans_check(&mut tree, jeans_check_time);
+    }
+
+    //times (in dynamical time) of the comparisons of the accelerations to the exact ones,
+    //made on force_check_size particules drawn at random
+    let force_checks = read_times(section, "force_check");
+    let force_check_size = get_or(section, "force_check_size", 1000);
 
     //run the simulation
-    simulation(&mut tree, time, folder.to_string(), crash_time);
+    simulation(
+        &mut tree,
+        time,
+        folder.to_string(),
+        crash_time,
+        &force_checks,
+        force_check_size,
+    );
 }
@@ -0,0 +1,223 @@
+//multipole moments of the nodes, around their center of mass
+//second moment S_ij = sum m y_i y_j: xx, xy, xz, yy, yz, zz
+//third moment T_ijk = sum m y_i y_j y_k: xxx, xxy, xxz, xyy, xyz, xzz, yyy, yyz, yzz, zzz
+
+//index of S_ij in the array of 6 values
+fn index2(i: usize, j: usize) -> usize {
+    let (i, j) = if i <= j { (i, j) } else { (j, i) };
+    match (i, j) {
+        (0, 0) => 0,
+        (0, 1) => 1,
+        (0, 2) => 2,
+        (1, 1) => 3,
+        (1, 2) => 4,
+        _ => 5,
+    }
+}
+
+//index of T_ijk in the array of 10 values
+fn index3(i: usize, j: usize, k: usize) -> usize {
+    let mut a = [i, j, k];
+    a.sort();
+    match (a[0], a[1], a[2]) {
+        (0, 0, 0) => 0,
+        (0, 0, 1) => 1,
+        (0, 0, 2) => 2,
+        (0, 1, 1) => 3,
+        (0, 1, 2) => 4,
+        (0, 2, 2) => 5,
+        (1, 1, 1) => 6,
+        (1, 1, 2) => 7,
+        (1, 2, 2) => 8,
+        _ => 9,
+    }
+}
+
+//add the moments of a kid of mass m, whose center of mass is at s from the center of mass
+//of the node (parallel axis theorem, the first moment of the kid is 0 around its center)
+pub fn add_shifted_moments(
+    quadrupole: &mut [f64; 6],
+    octupole: Option<&mut [f64; 10]>,
+    m: f64,
+    s: &[f64; 3],
+    kid_quadrupole: &[f64; 6],
+    kid_octupole: Option<&[f64; 10]>,
+) {
+    for i in 0..3 {
+        for j in i..3 {
+            quadrupole[index2(i, j)] += kid_quadrupole[index2(i, j)] + m * s[i] * s[j];
+        }
+    }
+    if let (Some(octupole), Some(kid_octupole)) = (octupole, kid_octupole) {
+        for i in 0..3 {
+            for j in i..3 {
+                for k in j..3 {
+                    octupole[index3(i, j, k)] += kid_octupole[index3(i, j, k)]
+                        + kid_quadrupole[index2(i, j)] * s[k]
+                        + kid_quadrupole[index2(i, k)] * s[j]
+                        + kid_quadrupole[index2(j, k)] * s[i]
+                        + m * s[i] * s[j] * s[k];
+                }
+            }
+        }
+    }
+}
+
+//acceleration and potential of the quadrupole (and octupole) terms of a node
+//r is the position of the particule relative to the center of mass of the node, d its norm
+//phi = -(M / r + 1/2 S_ij D_ij - 1/6 T_ijk D_ijk), D_ij.. the derivatives of 1 / r
+pub fn multipole_acceleration(
+    r: &[f64; 3],
+    d: f64,
+    quadrupole: &[f64; 6],
+    octupole: Option<&[f64; 10]>,
+) -> [f64; 4] {
+    let mut ap = [0f64; 4];
+    let d2 = d * d;
+    let d5 = d2 * d2 * d;
+    let d7 = d5 * d2;
+    let d9 = d7 * d2;
+
+    //quadrupole: trace, S.r and r.S.r
+    let trace = quadrupole[0] + quadrupole[3] + quadrupole[5];
+    let mut sr = [0f64; 3];
+    for i in 0..3 {
+        for j in 0..3 {
+            sr[i] += quadrupole[index2(i, j)] * r[j];
+        }
+    }
+    let rsr: f64 = (0..3).map(|i| r[i] * sr[i]).sum();
+    for i in 0..3 {
+        ap[i] += 0.5 * (-15. * r[i] * rsr / d7 + 3. * (r[i] * trace + 2. * sr[i]) / d5);
+    }
+    ap[3] -= 0.5 * (3. * rsr - d2 * trace) / d5;
+
+    //octupole: T_ijk r_i r_j r_k, T_ijk r_j r_k, T_ijj and T_ijj r_i
+    if let Some(octupole) = octupole {
+        let mut u = [0f64; 3];
+        let mut v = [0f64; 3];
+        for i in 0..3 {
+            for j in 0..3 {
+                v[i] += octupole[index3(i, j, j)];
+                for k in 0..3 {
+                    u[i] += octupole[index3(i, j, k)] * r[j] * r[k];
+                }
+            }
+        }
+        let tau: f64 = (0..3).map(|i| u[i] * r[i]).sum();
+        let w: f64 = (0..3).map(|i| v[i] * r[i]).sum();
+        for i in 0..3 {
+            ap[i] -= (-45. * u[i] / d7 + 105. * tau * r[i] / d9 + 9. * v[i] / d5
+                - 45. * w * r[i] / d7)
+                / 6.;
+        }
+        ap[3] += (-15. * tau / d7 + 9. * w / d5) / 6.;
+    }
+    ap
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::direct::*;
+    use crate::tree::*;
+
+    //moments of the point masses around c, by direct summation
+    fn moments(masses: &[f64], positions: &[[f64; 3]], c: &[f64; 3]) -> ([f64; 6], [f64; 10]) {
+        let mut quadrupole = [0f64; 6];
+        let mut octupole = [0f64; 10];
+        for (m, x) in masses.iter().zip(positions.iter()) {
+            let y = [x[0] - c[0], x[1] - c[1], x[2] - c[2]];
+            for i in 0..3 {
+                for j in i..3 {
+                    quadrupole[index2(i, j)] += m * y[i] * y[j];
+                    for k in j..3 {
+                        octupole[index3(i, j, k)] += m * y[i] * y[j] * y[k];
+                    }
+                }
+            }
+        }
+        (quadrupole, octupole)
+    }
+
+    fn center_of_mass(masses: &[f64], positions: &[[f64; 3]]) -> [f64; 3] {
+        let mass: f64 = masses.iter().sum();
+        let mut c = [0f64; 3];
+        for (m, x) in masses.iter().zip(positions.iter()) {
+            for i in 0..3 {
+                c[i] += m * x[i] / mass;
+            }
+        }
+        c
+    }
+
+    #[test]
+    fn shifted_moments_match_the_direct_moments() {
+        let masses = [1., 0.5, 2., 0.7, 1.3];
+        let positions = [
+            [0.1, -0.2, 0.3],
+            [-0.3, 0.1, 0.05],
+            [0.2, 0.25, -0.1],
+            [0.6, -0.3, -0.2],
+            [0.45, 0.1, 0.35],
+        ];
+        let c = center_of_mass(&masses, &positions);
+        let mut quadrupole = [0f64; 6];
+        let mut octupole = [0f64; 10];
+        //two kids: the first three masses and the last two
+        for &(start, end) in [(0, 3), (3, 5)].iter() {
+            let (masses, positions) = (&masses[start..end], &positions[start..end]);
+            let kid_c = center_of_mass(masses, positions);
+            let (kid_quadrupole, kid_octupole) = moments(masses, positions, &kid_c);
+            add_shifted_moments(
+                &mut quadrupole,
+                Some(&mut octupole),
+                masses.iter().sum(),
+                &[kid_c[0] - c[0], kid_c[1] - c[1], kid_c[2] - c[2]],
+                &kid_quadrupole,
+                Some(&kid_octupole),
+            );
+        }
+        let (direct_quadrupole, direct_octupole) = moments(&masses, &positions, &c);
+        for (a, b) in quadrupole
+            .iter()
+            .chain(octupole.iter())
+            .zip(direct_quadrupole.iter().chain(direct_octupole.iter()))
+        {
+            assert!((a - b).abs() < 1e-12, "{} != {}", a, b);
+        }
+    }
+
+    #[test]
+    fn higher_orders_reduce_the_force_errors() {
+        //mean relative error of the accelerations of the tree, at the same theta
+        let errors: Vec<f64> = (1..=3)
+            .map(|order| {
+                let tree = plummer_tree(Solver::Tree, order, 0.8);
+                let exact = direct_acceleration(
+                    &tree.particules,
+                    tree.kernel,
+                    tree.epsilon,
+                    &tree.softenings,
+                );
+                tree.particules
+                    .iter()
+                    .zip(exact.iter())
+                    .map(|(p, ap)| {
+                        let da = (0..3)
+                            .map(|i| (p.acceleration[i] - ap[i]).powi(2))
+                            .sum::<f64>()
+                            .sqrt();
+                        da / (0..3).map(|i| ap[i] * ap[i]).sum::<f64>().sqrt()
+                    })
+                    .sum::<f64>()
+                    / tree.particules.len() as f64
+            })
+            .collect();
+        assert!(
+            errors[1] < 0.7 * errors[0] && errors[2] < 0.7 * errors[1],
+            "{:?}",
+            errors
+        );
+    }
+}
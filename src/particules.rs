@@ -1,8 +1,26 @@
+use csv;
 use rand_distr::StandardNormal;
 use std::io;
-use csv;
 
+use crate::galaxy::*;
+use crate::jeans::*;
+use crate::planetary::*;
+use crate::profiles::*;
 use crate::rand::Rng;
+use crate::rayon::prelude::*;
+use crate::shells::*;
+use crate::streams::*;
+
+//component the particule belongs to
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Component {
+    Cluster,
+    Disk,
+    Bulge,
+    Halo,
+    //central body of a planetary system
+    Central,
+}
 
 #[derive(Debug, Copy, Clone)]
 pub struct Particule {
@@ -13,6 +31,7 @@ pub struct Particule {
     pub cinetic: f64,
     pub potential: f64,
     pub mass: f64,
+    pub component: Component,
 }
 
 //generate nb particules with the data
@@ -48,6 +67,7 @@ fn from_csv_gen(nb: usize) -> Vec<Particule> {
             cinetic: 0f64,
             potential: 0f64,
             mass: 1. / (nb as f64),
+            component: Component::Cluster,
         });
         c = c + 1;
     }
@@ -60,16 +80,14 @@ fn from_csv_gen(nb: usize) -> Vec<Particule> {
 //generate nb particules with uniform distribution of velocities and positions on the unit sphere
 //all particules have the same mass = 1/nb
 //speed is uniform and ||v|| < 1
-fn unif_gen(nb: usize) -> Vec<Particule> {
-    let mut rng = rand::thread_rng();
-    let mut particules = Vec::with_capacity(nb);
-    let mut x;
-    let mut y;
-    let mut z;
-    let mut vx;
-    let mut vy;
-    let mut vz;
-    for _ in 0..nb {
+fn unif_gen(nb: usize, seed: u64) -> Vec<Particule> {
+    par_generation(nb, seed, |rng, _| {
+        let mut x;
+        let mut y;
+        let mut z;
+        let mut vx;
+        let mut vy;
+        let mut vz;
         loop {
             x = rng.gen_range(-1., 1.);
             y = rng.gen_range(-1., 1.);
@@ -86,16 +104,16 @@ fn unif_gen(nb: usize) -> Vec<Particule> {
                 break;
             }
         }
-        particules.push(Particule {
+        Particule {
             position: [x, y, z],
             speed: [vx, vy, vz],
             acceleration: [0., 0., 0.],
             cinetic: 0f64,
             potential: 0f64,
             mass: 1. / (nb as f64),
-        });
-    }
-    particules
+            component: Component::Cluster,
+        }
+    })
 }
 
 //UNTESTED
@@ -147,16 +165,15 @@ fn henon_gen(nb: usize) -> Vec<Particule> {
             cinetic: 0f64,
             potential: 0f64,
             mass: 1. / (nb as f64),
+            component: Component::Cluster,
         });
     }
     particules
 }
 
 //generate a Plummer
-fn plummer(nb: usize) -> Vec<Particule> {
-    let mut rng = rand::thread_rng();
-    let mut particules = Vec::with_capacity(nb);
-    for _ in 0..nb {
+fn plummer(nb: usize, seed: u64) -> Vec<Particule> {
+    par_generation(nb, seed, |rng, _| {
         let x1 = rng.gen_range(0., 1.);
         let r = ((0.99f64 * x1).powf(-2. / 3.) - 1f64).powf(-1. / 2.);
         let x2 = rng.gen_range(0., 1.);
@@ -187,29 +204,83 @@ fn plummer(nb: usize) -> Vec<Particule> {
         let u = f64::sqrt(v * v - w * w) * f64::cos(2f64 * std::f64::consts::PI * x7);
         let uu = f64::sqrt(v * v - w * w) * f64::sin(2f64 * std::f64::consts::PI * x7);
 
-        particules.push(Particule {
+        Particule {
             position: [x, y, z],
             speed: [w, u, uu],
             acceleration: [0f64, 0f64, 0f64],
             cinetic: 0f64,
             potential: 0f64,
             mass: 1. / (nb as f64),
-        });
+            component: Component::Cluster,
+        }
+    })
+}
+
+//return the position and the speed of the center of mass
+pub fn center_of_mass(particules: &[Particule]) -> ([f64; 3], [f64; 3]) {
+    let mut position = [0f64; 3];
+    let mut speed = [0f64; 3];
+    let mut mass = 0f64;
+    for p in particules.iter() {
+        for i in 0..3 {
+            position[i] += p.mass * p.position[i];
+            speed[i] += p.mass * p.speed[i];
+        }
+        mass += p.mass;
     }
-    particules
+    for i in 0..3 {
+        position[i] /= mass;
+        speed[i] /= mass;
+    }
+    (position, speed)
+}
+
+//move the particules to the barycentric frame:
+//the center of mass is put at the origin and at rest
+//return the position and speed that were removed
+pub fn recenter(particules: &mut [Particule]) -> ([f64; 3], [f64; 3]) {
+    let (position, speed) = center_of_mass(particules);
+    particules.par_iter_mut().for_each(|p| {
+        for i in 0..3 {
+            p.position[i] -= position[i];
+            p.speed[i] -= speed[i];
+        }
+    });
+    (position, speed)
+}
+
+//model used for the initial conditions
+#[derive(Debug, Clone)]
+pub enum Model {
+    Uniform,
+    Plummer,
+    //read from a .csv file provided by stdin (<)
+    Csv,
+    Galaxy(Galaxy),
+    Shells(Shells),
+    //polytrope or truncated isothermal sphere
+    Isotropic(DistributionFunction),
+    //any density and anisotropy, speeds from the Jeans equation
+    Jeans(Jeans),
+    //central mass with a disk of light bodies
+    Planetary(Planetary),
 }
 
-pub fn generation(nb: usize, is_plummer: bool, from_csv: bool) -> Vec<Particule> {
+//the models only depend on the seed (except the csv one)
+pub fn generation(nb: usize, model: &Model, seed: u64) -> Vec<Particule> {
     let particules;
-    if from_csv {
-        particules = from_csv_gen(nb);
-    } else {
-        if is_plummer {
-            particules = plummer(nb);
-        } else {
-            particules = unif_gen(nb);
+    match model {
+        Model::Csv => particules = from_csv_gen(nb),
+        Model::Plummer => particules = plummer(nb, seed),
+        Model::Uniform => {
+            particules = unif_gen(nb, seed);
             //particules = henon_gen(nb);
         }
+        Model::Galaxy(galaxy) => particules = galaxy_gen(nb, galaxy, seed),
+        Model::Shells(shells) => particules = shells_gen(nb, shells, seed),
+        Model::Isotropic(df) => particules = df_gen(nb, df, seed),
+        Model::Jeans(jeans) => particules = jeans_gen(nb, jeans, seed),
+        Model::Planetary(planetary) => particules = planetary_gen(nb, planetary, seed),
     }
     return particules;
 }
@@ -0,0 +1,214 @@
+use crate::particules::*;
+use crate::rayon::prelude::*;
+use crate::tree::*;
+
+//quantity perturbed by the mode
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Mode {
+    //the masses are multiplied by 1 + amplitude f(r) Y_lm
+    Density,
+    //radial speed amplitude f(r) Y_lm v_c(r), v_c(r) = sqrt(G M(<r) / r)
+    Velocity,
+}
+
+//radial shape f(r) of the perturbation
+#[derive(Debug, Copy, Clone)]
+pub enum RadialShape {
+    Uniform,
+    //exp(-(r - radius)^2 / (2 width^2))
+    Gaussian { radius: f64, width: f64 },
+}
+
+//perturbation of order (l, m) of a model, centered on the origin of the model
+#[derive(Debug, Copy, Clone)]
+pub struct Perturbation {
+    pub l: usize,
+    pub m: i64,
+    pub mode: Mode,
+    pub shape: RadialShape,
+    pub amplitude: f64,
+}
+
+//real spherical harmonic Y_lm (orthonormal on the sphere)
+//cos(m phi) for m > 0, sin(|m| phi) for m < 0
+pub fn spherical_harmonic(l: usize, m: i64, cos_theta: f64, phi: f64) -> f64 {
+    let am = m.unsigned_abs() as usize;
+    //associated Legendre function P_l^|m|, by recurrence on l
+    let sin_theta = f64::max(1. - cos_theta * cos_theta, 0.).sqrt();
+    let mut p_mm = 1f64;
+    for i in 0..am {
+        p_mm *= (2 * i + 1) as f64 * sin_theta;
+    }
+    let p_lm = if l == am {
+        p_mm
+    } else {
+        let mut p_prev = p_mm;
+        let mut p = (2 * am + 1) as f64 * cos_theta * p_mm;
+        for ll in am + 2..=l {
+            let p_next = ((2 * ll - 1) as f64 * cos_theta * p - (ll + am - 1) as f64 * p_prev)
+                / (ll - am) as f64;
+            p_prev = p;
+            p = p_next;
+        }
+        p
+    };
+    //normalization sqrt((2l + 1) / (4 pi) (l - |m|)! / (l + |m|)!)
+    let ratio: f64 = (l - am + 1..=l + am).map(|i| 1. / i as f64).product();
+    let norm = ((2 * l + 1) as f64 / (4. * std::f64::consts::PI) * ratio).sqrt();
+    if m > 0 {
+        std::f64::consts::SQRT_2 * norm * p_lm * (m as f64 * phi).cos()
+    } else if m < 0 {
+        std::f64::consts::SQRT_2 * norm * p_lm * (am as f64 * phi).sin()
+    } else {
+        norm * p_lm
+    }
+}
+
+impl Perturbation {
+    fn shape(&self, r: f64) -> f64 {
+        match self.shape {
+            RadialShape::Uniform => 1.,
+            RadialShape::Gaussian { radius, width } => {
+                (-(r - radius) * (r - radius) / (2. * width * width)).exp()
+            }
+        }
+    }
+
+    //f(r) Y_lm at the position x, relative to the center
+    fn mode(&self, x: &[f64; 3]) -> f64 {
+        let r = (x[0] * x[0] + x[1] * x[1] + x[2] * x[2]).sqrt();
+        if r == 0. {
+            return 0.;
+        }
+        self.shape(r) * spherical_harmonic(self.l, self.m, x[2] / r, x[1].atan2(x[0]))
+    }
+}
+
+//positions relative to the center, radial unit vectors and circular speeds of the particules
+fn radial_frame(particules: &[Particule], center: &[f64; 3]) -> Vec<([f64; 3], [f64; 3], f64)> {
+    let mut radii: Vec<(f64, usize)> = particules
+        .iter()
+        .enumerate()
+        .map(|(id, p)| {
+            let r = (0..3)
+                .map(|i| (p.position[i] - center[i]).powf(2.))
+                .sum::<f64>()
+                .sqrt();
+            (r, id)
+        })
+        .collect();
+    radii.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+    let mut v_c = vec![0f64; particules.len()];
+    let mut mass = 0.;
+    for (r, id) in radii.iter() {
+        mass += particules[*id].mass;
+        if *r > 0. {
+            v_c[*id] = (mass / r).sqrt();
+        }
+    }
+    particules
+        .iter()
+        .zip(v_c.iter())
+        .map(|(p, v)| {
+            let x = [
+                p.position[0] - center[0],
+                p.position[1] - center[1],
+                p.position[2] - center[2],
+            ];
+            let r = (x[0] * x[0] + x[1] * x[1] + x[2] * x[2]).sqrt();
+            let e_r = if r > 0. {
+                [x[0] / r, x[1] / r, x[2] / r]
+            } else {
+                [0f64; 3]
+            };
+            (x, e_r, *v)
+        })
+        .collect()
+}
+
+fn kinetic_energy(particules: &[Particule], speed: &[f64; 3]) -> f64 {
+    particules
+        .iter()
+        .map(|p| {
+            0.5 * p.mass
+                * (0..3)
+                    .map(|i| (p.speed[i] - speed[i]).powf(2.))
+                    .sum::<f64>()
+        })
+        .sum()
+}
+
+//apply the perturbation to the particules of the tree, around the origin of the model
+//the total mass, the momentum and the total energy (self-gravity only) are kept:
+//the masses are normalized and the speeds are scaled in the center of mass frame
+//(the potential energy is the one of the tree, see Tree::potential_energy)
+pub fn perturb(tree: &mut Tree, perturbation: &Perturbation) {
+    let (_, speed_init) = center_of_mass(&tree.particules);
+    let mass_init: f64 = tree.particules.iter().map(|p| p.mass).sum();
+    let potential_init = tree.potential_energy();
+    let energy_init = potential_init + kinetic_energy(&tree.particules, &speed_init);
+
+    let frame = radial_frame(&tree.particules, &tree.origin);
+    for (p, (x, e_r, v_c)) in tree.particules.iter_mut().zip(frame.iter()) {
+        let delta = perturbation.amplitude * perturbation.mode(x);
+        match perturbation.mode {
+            Mode::Density => {
+                if 1. + delta <= 0. {
+                    panic!("perturbation amplitude too large : negative mass");
+                }
+                p.mass *= 1. + delta;
+            }
+            Mode::Velocity => {
+                for i in 0..3 {
+                    p.speed[i] += delta * v_c * e_r[i];
+                }
+            }
+        }
+    }
+
+    let mass: f64 = tree.particules.iter().map(|p| p.mass).sum();
+    tree.particules
+        .iter_mut()
+        .for_each(|p| p.mass *= mass_init / mass);
+    //remove the momentum given by the perturbation (l = 1)
+    let (_, speed) = center_of_mass(&tree.particules);
+    tree.particules.iter_mut().for_each(|p| {
+        for i in 0..3 {
+            p.speed[i] += speed_init[i] - speed[i];
+        }
+    });
+    let potential = match perturbation.mode {
+        Mode::Density => tree.potential_energy(),
+        Mode::Velocity => potential_init,
+    };
+    let kinetic = energy_init - potential;
+    if kinetic <= 0. {
+        panic!("perturbation amplitude too large : the energy can't be kept");
+    }
+    let factor = (kinetic / kinetic_energy(&tree.particules, &speed_init)).sqrt();
+    tree.particules.iter_mut().for_each(|p| {
+        for i in 0..3 {
+            p.speed[i] = speed_init[i] + factor * (p.speed[i] - speed_init[i]);
+        }
+    });
+}
+
+//projection of the particules on the mode, around the origin of the model
+//sum m f Y_lm x / sum m (f Y_lm)^2, with x = 1 for the density and x = v_r / v_c for the speed,
+//so it gives back the amplitude of a small perturbation
+//return (density amplitude, velocity amplitude)
+pub fn mode_amplitudes(tree: &Tree, perturbation: &Perturbation) -> (f64, f64) {
+    let frame = radial_frame(&tree.particules, &tree.origin);
+    let (density, velocity, norm) = tree
+        .particules
+        .par_iter()
+        .zip(frame.par_iter())
+        .map(|(p, (x, e_r, v_c))| {
+            let y = perturbation.mode(x);
+            let v_r = (0..3).map(|i| p.speed[i] * e_r[i]).sum::<f64>();
+            let v = if *v_c > 0. { v_r / v_c } else { 0. };
+            (p.mass * y, p.mass * y * v, p.mass * y * y)
+        })
+        .reduce(|| (0., 0., 0.), |a, b| (a.0 + b.0, a.1 + b.1, a.2 + b.2));
+    (density / norm, velocity / norm)
+}
@@ -0,0 +1,123 @@
+use crate::binaries::*;
+use crate::particules::*;
+use crate::rand::Rng;
+use crate::streams::*;
+
+//heavy central body with a disk or a ring of light bodies on keplerian orbits
+#[derive(Debug, Copy, Clone)]
+pub struct Planetary {
+    pub central_mass: f64,
+    //total mass of the light bodies
+    pub disk_mass: f64,
+    //the semi-major axes are between a_min and a_max,
+    //with a surface density ~ a^-a_power (dN/da ~ a^(1 - a_power))
+    pub a_min: f64,
+    pub a_max: f64,
+    pub a_power: f64,
+    //rayleigh distributions of the eccentricity and of the inclination (in radians)
+    pub sigma_e: f64,
+    pub sigma_i: f64,
+    //masses of the light bodies: dN/dm ~ m^-mass_index, between m and mass_ratio * m
+    pub mass_ratio: f64,
+    pub mass_index: f64,
+}
+
+//draw x with dN/dx ~ x^(k - 1) between x_min and x_max
+fn sample_power_law<R: Rng>(rng: &mut R, x_min: f64, x_max: f64, k: f64) -> f64 {
+    let u: f64 = rng.gen_range(0f64, 1f64);
+    if k.abs() < 1e-12 {
+        x_min * (x_max / x_min).powf(u)
+    } else {
+        (x_min.powf(k) + u * (x_max.powf(k) - x_min.powf(k))).powf(1. / k)
+    }
+}
+
+//draw x from a rayleigh distribution of parameter sigma, below x_max
+fn sample_rayleigh<R: Rng>(rng: &mut R, sigma: f64, x_max: f64) -> f64 {
+    loop {
+        let u: f64 = rng.gen_range(0f64, 1f64);
+        let x = sigma * (-2. * (1. - u).ln()).sqrt();
+        if x < x_max {
+            return x;
+        }
+    }
+}
+
+//rotation from the plane of the orbit to the frame of the disk
+//(longitude of the ascending node, inclination, argument of the pericenter)
+fn orbit_rotation(node: f64, inclination: f64, pericenter: f64) -> [[f64; 3]; 3] {
+    let (so, co) = node.sin_cos();
+    let (si, ci) = inclination.sin_cos();
+    let (sw, cw) = pericenter.sin_cos();
+    [
+        [co * cw - so * sw * ci, -co * sw - so * cw * ci, so * si],
+        [so * cw + co * sw * ci, -so * sw + co * cw * ci, -co * si],
+        [sw * si, cw * si, ci],
+    ]
+}
+
+//generate the central body (first particule) and nb - 1 light bodies
+//the speed of the central body cancels the momentum of the light bodies
+pub fn planetary_gen(nb: usize, planetary: &Planetary, seed: u64) -> Vec<Particule> {
+    let nb_bodies = nb - 1;
+
+    let mut masses: Vec<f64> =
+        par_generation(nb_bodies, stream_seed(seed, STREAM_MASSES), |rng, _| {
+            sample_power_law(rng, 1., planetary.mass_ratio, 1. - planetary.mass_index)
+        });
+    let total: f64 = masses.iter().sum();
+    masses
+        .iter_mut()
+        .for_each(|m| *m *= planetary.disk_mass / total);
+
+    let mut particules = Vec::with_capacity(nb);
+    particules.push(Particule {
+        position: [0., 0., 0.],
+        speed: [0., 0., 0.],
+        acceleration: [0., 0., 0.],
+        cinetic: 0f64,
+        potential: 0f64,
+        mass: planetary.central_mass,
+        component: Component::Central,
+    });
+    particules.append(&mut par_generation(nb_bodies, seed, |rng, id| {
+        let mass = masses[id];
+        let a = sample_power_law(
+            rng,
+            planetary.a_min,
+            planetary.a_max,
+            2. - planetary.a_power,
+        );
+        let e = sample_rayleigh(rng, planetary.sigma_e, 1.);
+        let inclination = sample_rayleigh(rng, planetary.sigma_i, std::f64::consts::PI);
+        let node = 2. * std::f64::consts::PI * rng.gen_range(0f64, 1f64);
+        let pericenter = 2. * std::f64::consts::PI * rng.gen_range(0f64, 1f64);
+        let mean_anomaly = 2. * std::f64::consts::PI * rng.gen_range(0f64, 1f64);
+
+        let (r, v) = orbit_in_plane(planetary.central_mass + mass, a, e, mean_anomaly);
+        let rotation = orbit_rotation(node, inclination, pericenter);
+        let mut position = [0f64; 3];
+        let mut speed = [0f64; 3];
+        for i in 0..3 {
+            for j in 0..3 {
+                position[i] += rotation[i][j] * r[j];
+                speed[i] += rotation[i][j] * v[j];
+            }
+        }
+        Particule {
+            position: position,
+            speed: speed,
+            acceleration: [0., 0., 0.],
+            cinetic: 0f64,
+            potential: 0f64,
+            mass: mass,
+            component: Component::Disk,
+        }
+    }));
+
+    for i in 0..3 {
+        let momentum: f64 = particules[1..].iter().map(|p| p.mass * p.speed[i]).sum();
+        particules[0].speed[i] = -momentum / planetary.central_mass;
+    }
+    particules
+}
@@ -0,0 +1,472 @@
+use crate::particules::*;
+use crate::rayon::prelude::*;
+use crate::softening::*;
+
+//particle-mesh solver: the masses are assigned to a cubic grid (cloud in cell), the potential
+//is the convolution of the grid with the green function, computed with FFTs on a grid twice
+//as large (zero padding, so the boundary conditions are isolated), and the accelerations are
+//finite differences of the potential, interpolated back to the particules (cloud in cell)
+//for the TreePM solver, the mesh gives the long range part of the gaussian split:
+//phi_long(r) = -erf(r / (2 r_s)) / r, the tree computes the rest
+
+//number of empty cells on each side of the particules (for the finite differences)
+const MARGIN: usize = 4;
+//beyond CUT * r_s the short range part of the gaussian split is neglected (GADGET-2)
+pub const CUT: f64 = 4.5;
+
+#[derive(Debug, Copy, Clone)]
+struct Complex {
+    re: f64,
+    im: f64,
+}
+
+impl Complex {
+    fn mul(self, other: Complex) -> Complex {
+        Complex {
+            re: self.re * other.re - self.im * other.im,
+            im: self.re * other.im + self.im * other.re,
+        }
+    }
+}
+
+//in place FFT of a line whose length is a power of 2 (iterative Cooley-Tukey)
+//the inverse transform is not normalized
+fn fft(data: &mut [Complex], inverse: bool) {
+    let n = data.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            data.swap(i, j);
+        }
+    }
+    let sign = if inverse { 1. } else { -1. };
+    let mut length = 2;
+    while length <= n {
+        let angle = sign * 2. * std::f64::consts::PI / length as f64;
+        let w_length = Complex {
+            re: angle.cos(),
+            im: angle.sin(),
+        };
+        for start in (0..n).step_by(length) {
+            let mut w = Complex { re: 1., im: 0. };
+            for k in 0..length / 2 {
+                let u = data[start + k];
+                let v = data[start + k + length / 2].mul(w);
+                data[start + k] = Complex {
+                    re: u.re + v.re,
+                    im: u.im + v.im,
+                };
+                data[start + k + length / 2] = Complex {
+                    re: u.re - v.re,
+                    im: u.im - v.im,
+                };
+                w = w.mul(w_length);
+            }
+        }
+        length <<= 1;
+    }
+}
+
+//FFT of a cubic grid of side m, index (i * m + j) * m + k
+//the lines along k are transformed, then the axes are rotated, three times
+fn fft3(data: &mut Vec<Complex>, m: usize, inverse: bool) {
+    for _ in 0..3 {
+        data.par_chunks_mut(m).for_each(|line| fft(line, inverse));
+        //(i, j, k) -> (j, k, i)
+        let mut rotated = vec![Complex { re: 0., im: 0. }; data.len()];
+        rotated
+            .par_chunks_mut(m)
+            .enumerate()
+            .for_each(|(jk, line)| {
+                for (i, x) in line.iter_mut().enumerate() {
+                    *x = data[i * m * m + jk];
+                }
+            });
+        *data = rotated;
+    }
+}
+
+//erfc with a fractional error below 1.2e-7 (Numerical Recipes)
+fn erfc(x: f64) -> f64 {
+    let z = x.abs();
+    let t = 1. / (1. + 0.5 * z);
+    let r = t
+        * (-z * z - 1.26551223
+            + t * (1.00002368
+                + t * (0.37409196
+                    + t * (0.09678418
+                        + t * (-0.18628806
+                            + t * (0.27886807
+                                + t * (-1.13520398
+                                    + t * (1.48851587 + t * (-0.82215223 + t * 0.17087277)))))))))
+            .exp();
+    if x >= 0. {
+        r
+    } else {
+        2. - r
+    }
+}
+
+//long range part of the gaussian split at the distance r, same convention as the kernels:
+//(f, phi) with phi = -erf(r / (2 r_s)) / r and f = phi'(r) / r
+//a series is used for small r, where the direct formulas cancel
+pub fn long_range(r: f64, r_s: f64) -> (f64, f64) {
+    let x = r / (2. * r_s);
+    let c = 2. / std::f64::consts::PI.sqrt();
+    if x < 1. {
+        //erf(x) = c x sum (-1)^n x^2n / (n! (2n+1))
+        //erf(x) - c x exp(-x^2) = c x^3 sum (-1)^(n+1) 2n x^(2n-2) / (n! (2n+1)), n >= 1
+        let x2 = x * x;
+        let (mut s_phi, mut s_f) = (1., 0.);
+        //t = (-1)^(n+1) x^(2n-2) / n!
+        let mut t = 1.;
+        for n in 1..20 {
+            if n > 1 {
+                t *= -x2 / n as f64;
+            }
+            s_f += t * (2 * n) as f64 / (2 * n + 1) as f64;
+            s_phi -= t * x2 / (2 * n + 1) as f64;
+        }
+        (c * s_f / (8. * r_s * r_s * r_s), -c * s_phi / (2. * r_s))
+    } else {
+        let erf = 1. - erfc(x);
+        ((erf - c * x * (-x * x).exp()) / (r * r * r), -erf / r)
+    }
+}
+
+//cloud in cell: first cell and weights of the two cells along each axis
+fn cic(position: &[f64; 3], origin: &[f64; 3], h: f64) -> ([usize; 3], [[f64; 2]; 3]) {
+    let mut cell = [0usize; 3];
+    let mut weights = [[0f64; 2]; 3];
+    for i in 0..3 {
+        let u = (position[i] - origin[i]) / h - 0.5;
+        let u0 = u.floor();
+        cell[i] = u0 as usize;
+        weights[i] = [1. - (u - u0), u - u0];
+    }
+    (cell, weights)
+}
+
+//acceleration and potential of all the particules from a mesh of n^3 cells around them
+//split: r_s / h for the long range part of TreePM, None for the full interaction
+//(the green function is then the softening kernel, with a softening of at least one cell)
+//return them with r_s (0 without split)
+pub fn pm_acceleration(
+    particules: &[Particule],
+    n: usize,
+    split: Option<f64>,
+    kernel: Kernel,
+    epsilon: f64,
+) -> (Vec<[f64; 4]>, f64) {
+    //cube around the particules, with a margin of empty cells
+    let mut min = [f64::INFINITY; 3];
+    let mut max = [f64::NEG_INFINITY; 3];
+    for p in particules.iter() {
+        for i in 0..3 {
+            min[i] = f64::min(min[i], p.position[i]);
+            max[i] = f64::max(max[i], p.position[i]);
+        }
+    }
+    let extent = (0..3).map(|i| max[i] - min[i]).fold(0f64, f64::max);
+    //all the particules at the same point: the accelerations are 0 whatever the size of the cells
+    let extent = if extent > 0. {
+        extent
+    } else if epsilon > 0. {
+        epsilon
+    } else {
+        1.
+    };
+    let h = extent / (n - 2 * MARGIN) as f64 * (1. + 1e-9);
+    let origin = [
+        0.5 * (min[0] + max[0]) - 0.5 * n as f64 * h,
+        0.5 * (min[1] + max[1]) - 0.5 * n as f64 * h,
+        0.5 * (min[2] + max[2]) - 0.5 * n as f64 * h,
+    ];
+    let r_s = split.map(|factor| factor * h).unwrap_or(0.);
+
+    //green function on the padded grid of side m, with the nearest image of each cell
+    let m = 2 * n;
+    let green = |r: f64| match split {
+        Some(_) => long_range(r, r_s).1,
+        None => kernel.kernel(r, f64::max(epsilon, h)).1,
+    };
+    let mut green_grid = vec![Complex { re: 0., im: 0. }; m * m * m];
+    green_grid
+        .par_chunks_mut(m * m)
+        .enumerate()
+        .for_each(|(i, plane)| {
+            let image = |i: usize| {
+                if i <= n {
+                    i as f64
+                } else {
+                    i as f64 - m as f64
+                }
+            };
+            for j in 0..m {
+                for k in 0..m {
+                    let (x, y, z) = (image(i), image(j), image(k));
+                    plane[j * m + k].re = green(h * (x * x + y * y + z * z).sqrt());
+                }
+            }
+        });
+    fft3(&mut green_grid, m, false);
+    //for the split, the assignment and the interpolation are deconvolved
+    //(the gaussian removes the small scales where the deconvolution would add noise)
+    if split.is_some() {
+        let window = |i: usize| {
+            let k = if i <= m / 2 {
+                i as f64
+            } else {
+                i as f64 - m as f64
+            };
+            let x = std::f64::consts::PI * k / m as f64;
+            if k == 0. {
+                1.
+            } else {
+                (x.sin() / x).powi(2)
+            }
+        };
+        green_grid
+            .par_chunks_mut(m * m)
+            .enumerate()
+            .for_each(|(i, plane)| {
+                for j in 0..m {
+                    for k in 0..m {
+                        let w = window(i) * window(j) * window(k);
+                        plane[j * m + k].re /= w * w;
+                        plane[j * m + k].im /= w * w;
+                    }
+                }
+            });
+    }
+    let normalization = 1. / (m * m * m) as f64;
+
+    //green function seen by the mesh between two cells at the offsets (0, 0, 0), (0, 0, 1),
+    //(0, 1, 1) and (1, 1, 1), for the potential of each particule on itself
+    //(it has the symmetries of the cube, so it only depends on the number of nonzero offsets)
+    let self_green: [f64; 4] = match split {
+        None => [0., 1., 2., 3.].map(|d2: f64| green(h * d2.sqrt())),
+        Some(_) => {
+            let mut deconvolved = green_grid.clone();
+            fft3(&mut deconvolved, m, true);
+            [0, 1, m + 1, m * m + m + 1].map(|id| deconvolved[id].re * normalization)
+        }
+    };
+
+    //masses of the cells
+    let mut density = vec![Complex { re: 0., im: 0. }; m * m * m];
+    for p in particules.iter() {
+        let (cell, weights) = cic(&p.position, &origin, h);
+        for a in 0..2 {
+            for b in 0..2 {
+                for c in 0..2 {
+                    let id = ((cell[0] + a) * m + cell[1] + b) * m + cell[2] + c;
+                    density[id].re += p.mass * weights[0][a] * weights[1][b] * weights[2][c];
+                }
+            }
+        }
+    }
+    fft3(&mut density, m, false);
+
+    //convolution
+    density
+        .par_iter_mut()
+        .zip(green_grid.par_iter())
+        .for_each(|(x, g)| *x = x.mul(*g));
+    fft3(&mut density, m, true);
+    let potential = |i: usize, j: usize, k: usize| density[(i * m + j) * m + k].re * normalization;
+
+    //acceleration in the cells: -grad phi, with 4 points finite differences
+    let mut fields = vec![[0f64; 4]; n * n * n];
+    fields.par_iter_mut().enumerate().for_each(|(id, field)| {
+        let (i, j, k) = (id / (n * n), (id / n) % n, id % n);
+        field[3] = potential(i, j, k);
+        if i < 2 || j < 2 || k < 2 || i + 2 >= n || j + 2 >= n || k + 2 >= n {
+            return;
+        }
+        field[0] = -(8. * (potential(i + 1, j, k) - potential(i - 1, j, k))
+            - (potential(i + 2, j, k) - potential(i - 2, j, k)))
+            / (12. * h);
+        field[1] = -(8. * (potential(i, j + 1, k) - potential(i, j - 1, k))
+            - (potential(i, j + 2, k) - potential(i, j - 2, k)))
+            / (12. * h);
+        field[2] = -(8. * (potential(i, j, k + 1) - potential(i, j, k - 1))
+            - (potential(i, j, k + 2) - potential(i, j, k - 2)))
+            / (12. * h);
+    });
+
+    //interpolation back to the particules, the potential of the particule on itself is removed:
+    //its mass is assigned with the weights w_c and the potential interpolated with the same ones,
+    //so it is m sum w_c w_c' G(c - c'), with a weight w_0^2 + w_1^2 (same cell)
+    //or w_0 w_1 (next cell) along each axis
+    let aps = particules
+        .par_iter()
+        .map(|p| {
+            let (cell, weights) = cic(&p.position, &origin, h);
+            let mut ap = [0f64; 4];
+            for a in 0..2 {
+                for b in 0..2 {
+                    for c in 0..2 {
+                        let w = weights[0][a] * weights[1][b] * weights[2][c];
+                        let field = &fields[((cell[0] + a) * n + cell[1] + b) * n + cell[2] + c];
+                        for i in 0..4 {
+                            ap[i] += w * field[i];
+                        }
+                    }
+                }
+            }
+            //offsets -1, 0 and 1 of the two cells along each axis (index 1 for the same cell)
+            let mut self_potential = 0.;
+            for a in 0..3 {
+                for b in 0..3 {
+                    for c in 0..3 {
+                        let offsets = [a, b, c];
+                        let mut w = 1.;
+                        for i in 0..3 {
+                            let [w0, w1] = weights[i];
+                            w *= if offsets[i] == 1 {
+                                w0 * w0 + w1 * w1
+                            } else {
+                                w0 * w1
+                            };
+                        }
+                        let d2 = offsets.iter().filter(|&&o| o != 1).count();
+                        self_potential += w * self_green[d2];
+                    }
+                }
+            }
+            ap[3] -= p.mass * self_potential;
+            ap
+        })
+        .collect();
+    (aps, r_s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::direct::*;
+    use crate::tree::*;
+
+    fn particule(position: [f64; 3], mass: f64) -> Particule {
+        Particule {
+            position,
+            speed: [0., 0., 0.],
+            acceleration: [0., 0., 0.],
+            cinetic: 0.,
+            potential: 0.,
+            mass,
+            component: Component::Cluster,
+        }
+    }
+
+    #[test]
+    fn fft_matches_the_direct_transform() {
+        let n = 16;
+        let data: Vec<Complex> = (0..n)
+            .map(|i| Complex {
+                re: (0.7 * i as f64).sin() + 0.1 * i as f64,
+                im: (1.3 * i as f64).cos(),
+            })
+            .collect();
+        let mut transform = data.clone();
+        fft(&mut transform, false);
+        for (k, x) in transform.iter().enumerate() {
+            let mut direct = Complex { re: 0., im: 0. };
+            for (j, y) in data.iter().enumerate() {
+                let angle = -2. * std::f64::consts::PI * (j * k) as f64 / n as f64;
+                let z = y.mul(Complex {
+                    re: angle.cos(),
+                    im: angle.sin(),
+                });
+                direct.re += z.re;
+                direct.im += z.im;
+            }
+            assert!((x.re - direct.re).abs() < 1e-12 && (x.im - direct.im).abs() < 1e-12);
+        }
+
+        //round trip of the 3D transform
+        let m = 8;
+        let grid: Vec<Complex> = (0..m * m * m)
+            .map(|i| Complex {
+                re: (0.37 * i as f64).sin(),
+                im: 0.,
+            })
+            .collect();
+        let mut round_trip = grid.clone();
+        fft3(&mut round_trip, m, false);
+        fft3(&mut round_trip, m, true);
+        for (x, y) in round_trip.iter().zip(grid.iter()) {
+            assert!((x.re / (m * m * m) as f64 - y.re).abs() < 1e-12);
+            assert!((x.im / (m * m * m) as f64).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn point_mass_is_newtonian_far_from_the_cell() {
+        //unit mass at the origin, massless probes 25 cells away
+        let mut particules = vec![particule([0., 0., 0.], 1.)];
+        let directions = [
+            [1., 0., 0.],
+            [0., -1., 0.],
+            [0., 0., 1.],
+            [0.6, 0.8, 0.],
+            [-0.48, 0.6, -0.64],
+        ];
+        for u in directions.iter() {
+            particules.push(particule(*u, 0.));
+        }
+        let (aps, _) = pm_acceleration(&particules, 64, None, Kernel::Dehnen, 1e-3);
+        for (u, ap) in directions.iter().zip(aps[1..].iter()) {
+            for i in 0..3 {
+                assert!((ap[i] + u[i]).abs() < 1e-2, "{:?}", ap);
+            }
+            assert!((ap[3] + 1.).abs() < 1e-2, "{:?}", ap);
+        }
+    }
+
+    #[test]
+    fn coincident_particules_have_no_acceleration() {
+        let particules = vec![particule([0.5, 0.5, 0.5], 1.); 3];
+        let (aps, _) = pm_acceleration(&particules, 16, None, Kernel::Plummer, 0.1);
+        for ap in aps.iter() {
+            assert!(ap.iter().all(|x| x.is_finite()), "{:?}", ap);
+            assert!(ap[..3].iter().all(|x| x.abs() < 1e-12), "{:?}", ap);
+        }
+    }
+
+    #[test]
+    fn treepm_agrees_with_the_direct_summation() {
+        //small theta, so the errors are mostly the ones of the mesh
+        let tree = plummer_tree(Solver::TreePm(64, 1.25), 1, 0.3);
+        let exact = direct_acceleration(
+            &tree.particules,
+            tree.kernel,
+            tree.epsilon,
+            &tree.softenings,
+        );
+        let (mut errors, mut potential_errors) = (Vec::new(), Vec::new());
+        for (p, ap) in tree.particules.iter().zip(exact.iter()) {
+            let da = (0..3)
+                .map(|i| (p.acceleration[i] - ap[i]).powi(2))
+                .sum::<f64>()
+                .sqrt();
+            errors.push(da / (0..3).map(|i| ap[i] * ap[i]).sum::<f64>().sqrt());
+            potential_errors.push((p.potential - ap[3]).abs() / ap[3].abs());
+        }
+        let mean = errors.iter().sum::<f64>() / errors.len() as f64;
+        let potential_mean = potential_errors.iter().sum::<f64>() / errors.len() as f64;
+        assert!(mean < 1e-2, "mean error {}", mean);
+        assert!(
+            potential_mean < 3e-3,
+            "mean potential error {}",
+            potential_mean
+        );
+    }
+}
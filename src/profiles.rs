@@ -0,0 +1,240 @@
+use crate::particules::*;
+use crate::rand::Rng;
+use crate::streams::*;
+
+//isotropic models defined by a distribution function f(E), E = psi - v^2 / 2
+//psi is the relative potential (-potential, 0 at the edge of the model)
+#[derive(Debug, Copy, Clone)]
+pub enum DistributionFunction {
+    //polytrope of index n: f(E) ~ E^(n - 3/2), rho ~ psi^n (n = 5 is the Plummer model)
+    Polytrope(f64),
+    //truncated isothermal sphere (Woolley 1954): f(E) ~ exp(E / sigma^2), sigma = 1
+    //the parameter is the central relative potential W0
+    Woolley(f64),
+}
+
+//error function, approximation from Abramowitz & Stegun 7.1.26
+fn erf(x: f64) -> f64 {
+    let t = 1. / (1. + 0.3275911 * x.abs());
+    let y = 1.
+        - t * (0.254829592
+            + t * (-0.284496736 + t * (1.421413741 + t * (-1.453152027 + t * 1.061405429))))
+            * (-x * x).exp();
+    if x < 0. {
+        -y
+    } else {
+        y
+    }
+}
+
+impl DistributionFunction {
+    //distribution function, up to a constant
+    fn df(&self, e: f64) -> f64 {
+        if e <= 0. {
+            return 0.;
+        }
+        match self {
+            DistributionFunction::Polytrope(n) => e.powf(n - 1.5),
+            DistributionFunction::Woolley(_) => e.exp(),
+        }
+    }
+
+    //density as a function of the relative potential, with the same constant as df
+    //(up to a factor 4 pi sqrt(2) for the polytrope, which only changes the units)
+    pub fn density(&self, psi: f64) -> f64 {
+        if psi <= 0. {
+            return 0.;
+        }
+        match self {
+            DistributionFunction::Polytrope(n) => psi.powf(*n),
+            //4 pi int_0^sqrt(2 psi) exp(psi - v^2 / 2) v^2 dv
+            DistributionFunction::Woolley(_) => {
+                if psi < 0.01 {
+                    //series expansion, to avoid the cancellation near the edge
+                    4. * std::f64::consts::PI * (2. * psi).powf(1.5) / 3. * (1. + 0.4 * psi)
+                } else {
+                    4. * std::f64::consts::PI
+                        * (psi.exp() * (std::f64::consts::PI / 2.).sqrt() * erf(psi.sqrt())
+                            - (2. * psi).sqrt())
+                }
+            }
+        }
+    }
+
+    //relative potential at the center
+    fn psi_0(&self) -> f64 {
+        match self {
+            DistributionFunction::Polytrope(_) => 1.,
+            DistributionFunction::Woolley(w0) => *w0,
+        }
+    }
+}
+
+//tabulated spherical model
+//radii, relative potential and enclosed mass, from the center to the edge
+pub struct Profile {
+    pub radii: Vec<f64>,
+    pub psi: Vec<f64>,
+    pub mass: Vec<f64>,
+}
+
+impl Profile {
+    //solve the Poisson equation psi'' + 2 psi' / r = -4 pi rho(psi) (G = 1)
+    //from the center, with a 4th order Runge-Kutta, until psi = 0
+    //untruncated models (polytropes with n >= 5) are stopped at 10^4 core radii
+    pub fn from_df(df: &DistributionFunction) -> Profile {
+        let psi_0 = df.psi_0();
+        let rho_0 = df.density(psi_0);
+        let r_core = (9. / (4. * std::f64::consts::PI * rho_0)).sqrt();
+        let pi4 = 4. * std::f64::consts::PI;
+
+        //start with the series psi = psi_0 - 2 pi rho_0 r^2 / 3
+        let mut r = 1e-6 * r_core;
+        let mut y = [
+            psi_0 - 2. / 3. * std::f64::consts::PI * rho_0 * r * r,
+            -4. / 3. * std::f64::consts::PI * rho_0 * r,
+        ];
+        let mut profile = Profile {
+            radii: vec![0., r],
+            psi: vec![psi_0, y[0]],
+            mass: vec![0., -r * r * y[1]],
+        };
+        let derivative = |r: f64, y: [f64; 2]| [y[1], -pi4 * df.density(y[0]) - 2. * y[1] / r];
+
+        while r < 1e4 * r_core {
+            //the step follows the radius, so the grid is logarithmic far from the core
+            let h = 1e-3 * f64::max(r, r_core);
+            let k1 = derivative(r, y);
+            let k2 = derivative(
+                r + 0.5 * h,
+                [y[0] + 0.5 * h * k1[0], y[1] + 0.5 * h * k1[1]],
+            );
+            let k3 = derivative(
+                r + 0.5 * h,
+                [y[0] + 0.5 * h * k2[0], y[1] + 0.5 * h * k2[1]],
+            );
+            let k4 = derivative(r + h, [y[0] + h * k3[0], y[1] + h * k3[1]]);
+            let y_new = [
+                y[0] + h / 6. * (k1[0] + 2. * k2[0] + 2. * k3[0] + k4[0]),
+                y[1] + h / 6. * (k1[1] + 2. * k2[1] + 2. * k3[1] + k4[1]),
+            ];
+            if y_new[0] <= 0. {
+                //edge of the model, where psi = 0
+                let r_t = r + h * y[0] / (y[0] - y_new[0]);
+                profile.radii.push(r_t);
+                profile.psi.push(0.);
+                profile.mass.push(-r_t * r_t * y_new[1]);
+                break;
+            }
+            r += h;
+            y = y_new;
+            profile.radii.push(r);
+            profile.psi.push(y[0]);
+            profile.mass.push(-r * r * y[1]);
+        }
+        profile
+    }
+
+    pub fn total_mass(&self) -> f64 {
+        *self.mass.last().unwrap()
+    }
+
+    //potential energy -int G M(r) / r dM
+    pub fn potential_energy(&self) -> f64 {
+        let mut w = 0.;
+        for i in 1..self.radii.len() - 1 {
+            let dm = self.mass[i + 1] - self.mass[i];
+            w -= 0.5 * (self.mass[i] / self.radii[i] + self.mass[i + 1] / self.radii[i + 1]) * dm;
+        }
+        w
+    }
+
+    //linear interpolation of the table ys, at the index where xs = x
+    fn interpolate(xs: &[f64], ys: &[f64], x: f64) -> f64 {
+        let i = match xs.binary_search_by(|v| v.partial_cmp(&x).unwrap()) {
+            Ok(i) => return ys[i],
+            Err(i) => i,
+        };
+        if i == 0 {
+            return ys[0];
+        }
+        if i >= xs.len() {
+            return ys[xs.len() - 1];
+        }
+        let w = (x - xs[i - 1]) / (xs[i] - xs[i - 1]);
+        (1. - w) * ys[i - 1] + w * ys[i]
+    }
+
+    //draw a radius from the enclosed mass
+    //for untruncated models, the outermost 0.1% of the mass are not used
+    pub fn sample_radius<R: Rng>(&self, rng: &mut R) -> f64 {
+        let m_max = if *self.psi.last().unwrap() > 0. {
+            0.999 * self.total_mass()
+        } else {
+            self.total_mass()
+        };
+        let m = rng.gen_range(0., m_max);
+        Profile::interpolate(&self.mass, &self.radii, m)
+    }
+
+    pub fn psi_at(&self, r: f64) -> f64 {
+        Profile::interpolate(&self.radii, &self.psi, r)
+    }
+}
+
+//draw a random point on the sphere of radius r
+pub fn random_direction<R: Rng>(rng: &mut R, r: f64) -> [f64; 3] {
+    let x1: f64 = rng.gen_range(0., 1.);
+    let x2: f64 = rng.gen_range(0., 1.);
+    let z = (1. - 2. * x1) * r;
+    let x = f64::sqrt(r * r - z * z) * f64::cos(2. * std::f64::consts::PI * x2);
+    let y = f64::sqrt(r * r - z * z) * f64::sin(2. * std::f64::consts::PI * x2);
+    [x, y, z]
+}
+
+//draw the norm of the speed at the relative potential psi
+//the distribution v^2 f(psi - v^2 / 2) is sampled by rejection
+fn sample_speed<R: Rng>(rng: &mut R, df: &DistributionFunction, psi: f64) -> f64 {
+    if psi <= 0. {
+        return 0.;
+    }
+    let v_max = (2. * psi).sqrt();
+    let g = |v: f64| v * v * df.df(psi - 0.5 * v * v);
+    //upper bound of the distribution, with a safety margin
+    let g_max = 1.1
+        * (0..=50)
+            .map(|i| g(v_max * i as f64 / 50.))
+            .fold(0f64, f64::max);
+    loop {
+        let v = rng.gen_range(0., v_max);
+        if rng.gen_range(0., g_max) < g(v) {
+            return v;
+        }
+    }
+}
+
+//generate nb particules from an isotropic distribution function
+//in N-body units: total mass 1, potential energy -1/2 (G = 1)
+pub fn df_gen(nb: usize, df: &DistributionFunction, seed: u64) -> Vec<Particule> {
+    let profile = Profile::from_df(df);
+    let total_mass = profile.total_mass();
+    //factors to N-body units
+    let length_factor = -2. * profile.potential_energy() / (total_mass * total_mass);
+    let speed_factor = (1. / (total_mass * length_factor)).sqrt();
+
+    par_generation(nb, seed, |rng, _| {
+        let r = profile.sample_radius(rng);
+        let v = sample_speed(rng, df, profile.psi_at(r));
+        let position = random_direction(rng, length_factor * r);
+        let speed = random_direction(rng, speed_factor * v);
+        Particule {
+            position: position,
+            speed: speed,
+            acceleration: [0., 0., 0.],
+            cinetic: 0f64,
+            potential: 0f64,
+            mass: 1. / (nb as f64),
+            component: Component::Cluster,
+        }
+    })
+}
@@ -0,0 +1,139 @@
+use rand_chacha::ChaCha20Rng;
+use rand_distr::StandardNormal;
+
+use crate::particules::*;
+use crate::rand::Rng;
+use crate::streams::*;
+
+//change of the resolution of the initial conditions
+#[derive(Debug, Copy, Clone)]
+pub enum Resampling {
+    //keep a random fraction of the particules
+    Downsample(f64),
+    //split each particule in children
+    //jitter: velocity dispersion of the children, in units of the local velocity dispersion
+    Upsample { children: usize, jitter: f64 },
+}
+
+//keep a random fraction of the particules, the masses are rescaled to keep the total mass
+pub fn downsample(particules: &[Particule], fraction: f64, seed: u64) -> Vec<Particule> {
+    let nb = (fraction * particules.len() as f64).round() as usize;
+    let mut kept: Vec<Particule> =
+        par_sample(particules.len(), nb, stream_seed(seed, STREAM_RESAMPLING))
+            .iter()
+            .map(|&id| particules[id])
+            .collect();
+    let total_mass: f64 = particules.iter().map(|p| p.mass).sum();
+    let kept_mass: f64 = kept.iter().map(|p| p.mass).sum();
+    kept.iter_mut()
+        .for_each(|p| p.mass *= total_mass / kept_mass);
+    kept
+}
+
+//split each particule in children of equal masses
+//the children are scattered uniformly in a sphere of radius the distance to the farthest of
+//its neighbors (see Tree::nearest_neighbors), their center of mass is the position of the particule
+//the mass, the momentum and the kinetic energy are conserved:
+//each child gets the speed of its particule plus a jitter of zero sum,
+//then the speeds are scaled in the center of mass frame to recover the kinetic energy
+pub fn upsample(
+    particules: &[Particule],
+    neighbors: &[Vec<usize>],
+    children: usize,
+    jitter: f64,
+    seed: u64,
+) -> Vec<Particule> {
+    //the children of each particule are drawn in parallel
+    let children_of = |rng: &mut ChaCha20Rng, id: usize| {
+        let (p, neighbors) = (&particules[id], &neighbors[id]);
+        let h = neighbors
+            .iter()
+            .map(|id| {
+                (0..3)
+                    .map(|i| (p.position[i] - particules[*id].position[i]).powf(2.))
+                    .sum::<f64>()
+            })
+            .fold(0f64, f64::max)
+            .sqrt();
+        //local velocity dispersion (1D)
+        let sigma = (neighbors
+            .iter()
+            .map(|id| {
+                (0..3)
+                    .map(|i| (p.speed[i] - particules[*id].speed[i]).powf(2.))
+                    .sum::<f64>()
+            })
+            .sum::<f64>()
+            / (3 * usize::max(neighbors.len(), 1)) as f64)
+            .sqrt();
+
+        let mut offsets = vec![[0f64; 3]; children];
+        let mut kicks = vec![[0f64; 3]; children];
+        for (offset, kick) in offsets.iter_mut().zip(kicks.iter_mut()) {
+            //uniform in the sphere of radius h
+            loop {
+                for i in 0..3 {
+                    offset[i] = rng.gen_range(-1., 1.);
+                }
+                if offset.iter().map(|x| x * x).sum::<f64>() < 1. {
+                    break;
+                }
+            }
+            for i in 0..3 {
+                offset[i] *= h;
+                kick[i] = jitter * sigma * rng.sample::<f64, _>(StandardNormal);
+            }
+        }
+        //zero sum, to keep the center of mass and the momentum of the particule
+        for i in 0..3 {
+            let mean_offset = offsets.iter().map(|o| o[i]).sum::<f64>() / children as f64;
+            let mean_kick = kicks.iter().map(|k| k[i]).sum::<f64>() / children as f64;
+            offsets.iter_mut().for_each(|o| o[i] -= mean_offset);
+            kicks.iter_mut().for_each(|k| k[i] -= mean_kick);
+        }
+        offsets
+            .iter()
+            .zip(kicks.iter())
+            .map(|(offset, kick)| {
+                let mut child = *p;
+                child.mass = p.mass / children as f64;
+                for i in 0..3 {
+                    child.position[i] += offset[i];
+                    child.speed[i] += kick[i];
+                }
+                child
+            })
+            .collect::<Vec<Particule>>()
+    };
+    let mut new_particules: Vec<Particule> = par_generation(
+        particules.len(),
+        stream_seed(seed, STREAM_RESAMPLING),
+        children_of,
+    )
+    .into_iter()
+    .flatten()
+    .collect();
+
+    //recover the kinetic energy, in the center of mass frame
+    let kinetic = |particules: &[Particule], speed: &[f64; 3]| {
+        particules
+            .iter()
+            .map(|p| {
+                0.5 * p.mass
+                    * (0..3)
+                        .map(|i| (p.speed[i] - speed[i]).powf(2.))
+                        .sum::<f64>()
+            })
+            .sum::<f64>()
+    };
+    let (_, speed) = center_of_mass(particules);
+    let factor = (kinetic(particules, &speed) / kinetic(&new_particules, &speed)).sqrt();
+    if factor.is_finite() {
+        new_particules.iter_mut().for_each(|p| {
+            for i in 0..3 {
+                p.speed[i] = speed[i] + factor * (p.speed[i] - speed[i]);
+            }
+        });
+    }
+    new_particules
+}
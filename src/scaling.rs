@@ -0,0 +1,556 @@
+use crate::particules::*;
+use crate::rayon::prelude::*;
+
+//targets for the scaling of the initial conditions
+//a target set to None is left free
+//(G = 1, virial = 2 * kinetic / potential like in the simulation)
+#[derive(Debug, Copy, Clone)]
+pub struct Targets {
+    pub virial: Option<f64>,
+    pub energy: Option<f64>,
+    pub r50: Option<f64>,
+    //1D velocity dispersion of the particules inside R10
+    pub sigma_c: Option<f64>,
+    pub mass: Option<f64>,
+}
+
+impl Targets {
+    //true if no target is set, the initial conditions are left unchanged
+    pub fn is_empty(&self) -> bool {
+        self.virial.is_none()
+            && self.energy.is_none()
+            && self.r50.is_none()
+            && self.sigma_c.is_none()
+            && self.mass.is_none()
+    }
+}
+
+//values of the initial conditions after the scaling
+#[derive(Debug, Copy, Clone)]
+pub struct Scaling {
+    pub mass: f64,
+    pub r50: f64,
+    pub virial: f64,
+    pub energy: f64,
+    pub sigma_c: f64,
+    //factors applied to the masses, the positions and the speeds
+    pub mass_factor: f64,
+    pub length_factor: f64,
+    pub speed_factor: f64,
+}
+
+//measured properties of the initial conditions
+struct Measure {
+    mass: f64,
+    r50: f64,
+    kinetic: f64,
+    potential: f64,
+    sigma_c: f64,
+}
+
+//measure the initial conditions in the center of mass frame
+//the potential energy is given (see Tree::potential_energy)
+fn measure(particules: &[Particule], potential: f64) -> Measure {
+    let (position, speed) = center_of_mass(particules);
+    let mass: f64 = particules.iter().map(|p| p.mass).sum();
+    let kinetic: f64 = particules
+        .par_iter()
+        .map(|p| {
+            0.5 * p.mass
+                * (0..3)
+                    .map(|i| (p.speed[i] - speed[i]) * (p.speed[i] - speed[i]))
+                    .sum::<f64>()
+        })
+        .sum();
+
+    //sort the particules by distance to the center of mass
+    let mut distances: Vec<(f64, usize)> = particules
+        .par_iter()
+        .enumerate()
+        .map(|(id, p)| {
+            let d = (0..3)
+                .map(|i| (p.position[i] - position[i]) * (p.position[i] - position[i]))
+                .sum::<f64>()
+                .sqrt();
+            (d, id)
+        })
+        .collect();
+    distances.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+
+    //the half mass radius and the velocity dispersion inside R10
+    let mut r50 = 0f64;
+    let mut m = 0f64;
+    let mut m_c = 0f64;
+    let mut v_c = [0f64; 3];
+    let mut v2_c = 0f64;
+    for (d, id) in distances.iter() {
+        let p = &particules[*id];
+        if m < 0.1 * mass {
+            m_c += p.mass;
+            for i in 0..3 {
+                v_c[i] += p.mass * p.speed[i];
+                v2_c += p.mass * p.speed[i] * p.speed[i];
+            }
+        }
+        m += p.mass;
+        if m >= 0.5 * mass {
+            r50 = *d;
+            break;
+        }
+    }
+    let v_c2: f64 = v_c.iter().map(|v| (v / m_c) * (v / m_c)).sum();
+    let sigma_c = (f64::max(v2_c / m_c - v_c2, 0f64) / 3f64).sqrt();
+
+    Measure {
+        mass: mass,
+        r50: r50,
+        kinetic: kinetic,
+        potential: potential,
+        sigma_c: sigma_c,
+    }
+}
+
+fn det(rows: &[[f64; 3]; 3]) -> f64 {
+    rows[0][0] * (rows[1][1] * rows[2][2] - rows[1][2] * rows[2][1])
+        - rows[0][1] * (rows[1][0] * rows[2][2] - rows[1][2] * rows[2][0])
+        + rows[0][2] * (rows[1][0] * rows[2][1] - rows[1][1] * rows[2][0])
+}
+
+//rank of a set of at most 3 rows
+fn rank(rows: &[[f64; 3]]) -> usize {
+    match rows.len() {
+        0 => 0,
+        1 => 1,
+        2 => {
+            let (a, b) = (rows[0], rows[1]);
+            let cross = [
+                a[1] * b[2] - a[2] * b[1],
+                a[2] * b[0] - a[0] * b[2],
+                a[0] * b[1] - a[1] * b[0],
+            ];
+            if cross.iter().any(|c| c.abs() > 1e-12) {
+                2
+            } else {
+                1
+            }
+        }
+        _ => {
+            if det(&[rows[0], rows[1], rows[2]]).abs() > 1e-12 {
+                3
+            } else {
+                2
+            }
+        }
+    }
+}
+
+//find the factors (length, speed, mass) that reach the targets
+//the potential energy scales as mass^2 / length and the kinetic energy as mass * speed^2
+//so every target except the energy is a linear constraint on the logarithms of the factors
+fn solve(targets: &Targets, measure: &Measure) -> Result<(f64, f64, f64), String> {
+    let given: Vec<&str> = [
+        ("virial", targets.virial),
+        ("energy", targets.energy),
+        ("r50", targets.r50),
+        ("sigma_c", targets.sigma_c),
+        ("total_mass", targets.mass),
+    ]
+    .iter()
+    .filter(|(_, t)| t.is_some())
+    .map(|(name, _)| *name)
+    .collect();
+    if given.len() > 3 {
+        return Err(format!(
+            "only the mass, length and speed scales are free, so at most 3 targets can be set, got {:?}",
+            given
+        ));
+    }
+
+    //check each target alone
+    if let Some(mass) = targets.mass {
+        if mass <= 0. {
+            return Err(format!("total_mass must be positive, got {}", mass));
+        }
+    }
+    if let Some(r50) = targets.r50 {
+        if r50 <= 0. {
+            return Err(format!("r50 must be positive, got {}", r50));
+        }
+    }
+    if let Some(sigma_c) = targets.sigma_c {
+        if sigma_c <= 0. {
+            return Err(format!(
+                "sigma_c must be positive, got {} (use virial=0 for cold initial conditions)",
+                sigma_c
+            ));
+        }
+        if measure.sigma_c == 0. {
+            return Err("sigma_c can't be set, the initial conditions are cold".to_string());
+        }
+    }
+    let cold = targets.virial == Some(0.);
+    if let Some(virial) = targets.virial {
+        if virial > 0. {
+            return Err(format!(
+                "virial = 2 * kinetic / potential can't be positive, got {}",
+                virial
+            ));
+        }
+        if virial < 0. && measure.kinetic == 0. {
+            return Err("virial can't be set, the initial conditions are cold".to_string());
+        }
+        if cold && targets.sigma_c.is_some() {
+            return Err(
+                "virial=0 means cold initial conditions, so sigma_c can't be set".to_string(),
+            );
+        }
+    }
+
+    //linear constraints on (ln(length), ln(speed), ln(mass))
+    let mut rows: Vec<[f64; 3]> = Vec::new();
+    let mut values: Vec<f64> = Vec::new();
+    if let Some(virial) = targets.virial {
+        if cold {
+            //the speed factor is 0, the row only fixes the speed scale
+            rows.push([0., 1., 0.]);
+            values.push(0.);
+        } else {
+            let virial_init = 2. * measure.kinetic / measure.potential;
+            rows.push([1., 2., -1.]);
+            values.push((virial / virial_init).ln());
+        }
+        if let Some(energy) = targets.energy {
+            //with the virial ratio, the energy gives the potential energy
+            let potential = energy / (1. + 0.5 * virial);
+            if potential >= 0. {
+                return Err(format!(
+                    "energy={} and virial={} give a non negative potential energy",
+                    energy, virial
+                ));
+            }
+            rows.push([-1., 0., 2.]);
+            values.push((potential / measure.potential).ln());
+        }
+    }
+    if let Some(r50) = targets.r50 {
+        rows.push([1., 0., 0.]);
+        values.push((r50 / measure.r50).ln());
+    }
+    if let Some(sigma_c) = targets.sigma_c {
+        rows.push([0., 1., 0.]);
+        values.push((sigma_c / measure.sigma_c).ln());
+    }
+    if let Some(mass) = targets.mass {
+        rows.push([0., 0., 1.]);
+        values.push((mass / measure.mass).ln());
+    }
+    if rank(&rows) < rows.len() {
+        return Err(format!("the targets {:?} fix the same scale twice", given));
+    }
+
+    //without the virial ratio, the energy fixes the last free scale
+    let energy_alone = targets.energy.is_some() && targets.virial.is_none();
+    let needed = if energy_alone { 2 } else { 3 };
+
+    //the scales that are still free are left unchanged, in this order: mass, length, speed
+    for default in [[0., 0., 1.], [1., 0., 0.], [0., 1., 0.]].iter() {
+        if rows.len() == needed {
+            break;
+        }
+        let mut new_rows = rows.clone();
+        new_rows.push(*default);
+        if rank(&new_rows) == new_rows.len() {
+            rows = new_rows;
+            values.push(0.);
+        }
+    }
+
+    if energy_alone {
+        let energy = targets.energy.unwrap();
+        //rows are [1,0,0], [0,1,0] or [0,0,1]
+        let mut x = [None; 3];
+        for (row, value) in rows.iter().zip(values.iter()) {
+            let i = row.iter().position(|r| *r == 1.).unwrap();
+            x[i] = Some(value.exp());
+        }
+        let (t, w) = (measure.kinetic, measure.potential);
+        return match x {
+            [Some(a), Some(b), None] => {
+                //(w / a) m^2 + b^2 t m - energy = 0, keep the root closest to 1
+                let (qa, qb, qc) = (w / a, b * b * t, -energy);
+                let disc = qb * qb - 4. * qa * qc;
+                if disc < 0. {
+                    return Err(format!(
+                        "energy={} can't be reached by changing the mass",
+                        energy
+                    ));
+                }
+                let roots = [
+                    (-qb + disc.sqrt()) / (2. * qa),
+                    (-qb - disc.sqrt()) / (2. * qa),
+                ];
+                match roots
+                    .iter()
+                    .filter(|m| **m > 0.)
+                    .min_by(|m1, m2| (*m1 - 1.).abs().partial_cmp(&(*m2 - 1.).abs()).unwrap())
+                {
+                    Some(m) => Ok((a, b, *m)),
+                    None => Err(format!(
+                        "energy={} can't be reached by changing the mass",
+                        energy
+                    )),
+                }
+            }
+            [Some(a), None, Some(m)] => {
+                let kinetic = energy - m * m / a * w;
+                if kinetic < 0. || (kinetic > 0. && t == 0.) {
+                    return Err(format!(
+                        "energy={} can't be reached by changing the speeds, the potential energy is {}",
+                        energy,
+                        m * m / a * w
+                    ));
+                }
+                Ok((a, (kinetic / (m * t)).sqrt(), m))
+            }
+            [None, Some(b), Some(m)] => {
+                let potential = energy - m * b * b * t;
+                if potential >= 0. {
+                    return Err(format!(
+                        "energy={} can't be reached by changing the size, the kinetic energy is {}",
+                        energy,
+                        m * b * b * t
+                    ));
+                }
+                Ok((m * m * w / potential, b, m))
+            }
+            _ => unreachable!(),
+        };
+    }
+
+    //Cramer's rule
+    let matrix = [rows[0], rows[1], rows[2]];
+    let d = det(&matrix);
+    let mut x = [0f64; 3];
+    for i in 0..3 {
+        let mut m = matrix;
+        for j in 0..3 {
+            m[j][i] = values[j];
+        }
+        x[i] = det(&m) / d;
+    }
+    let speed_factor = if cold { 0. } else { x[1].exp() };
+    Ok((x[0].exp(), speed_factor, x[2].exp()))
+}
+
+//scale the masses, positions and speeds of the particules to reach the targets
+//positions and speeds are scaled around the center of mass
+//potential is the potential energy of the particules before the scaling
+pub fn scale(
+    particules: &mut [Particule],
+    targets: &Targets,
+    potential: f64,
+) -> Result<Scaling, String> {
+    let measure = measure(particules, potential);
+    let (length_factor, speed_factor, mass_factor) = solve(targets, &measure)?;
+    let (position, speed) = center_of_mass(particules);
+
+    particules.par_iter_mut().for_each(|p| {
+        p.mass *= mass_factor;
+        for i in 0..3 {
+            p.position[i] = position[i] + length_factor * (p.position[i] - position[i]);
+            p.speed[i] = speed[i] + speed_factor * (p.speed[i] - speed[i]);
+        }
+    });
+
+    let kinetic = mass_factor * speed_factor * speed_factor * measure.kinetic;
+    let potential = mass_factor * mass_factor / length_factor * measure.potential;
+    Ok(Scaling {
+        mass: mass_factor * measure.mass,
+        r50: length_factor * measure.r50,
+        virial: 2. * kinetic / potential,
+        energy: kinetic + potential,
+        sigma_c: speed_factor * measure.sigma_c,
+        mass_factor: mass_factor,
+        length_factor: length_factor,
+        speed_factor: speed_factor,
+    })
+}
+
+//values of the initial conditions when there is no target (all the factors are 1)
+pub fn unscaled(particules: &[Particule], potential: f64) -> Scaling {
+    let measure = measure(particules, potential);
+    Scaling {
+        mass: measure.mass,
+        r50: measure.r50,
+        virial: 2. * measure.kinetic / measure.potential,
+        energy: measure.kinetic + measure.potential,
+        sigma_c: measure.sigma_c,
+        mass_factor: 1.,
+        length_factor: 1.,
+        speed_factor: 1.,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn targets() -> Targets {
+        Targets {
+            virial: None,
+            energy: None,
+            r50: None,
+            sigma_c: None,
+            mass: None,
+        }
+    }
+
+    fn measure() -> Measure {
+        Measure {
+            mass: 2.,
+            r50: 0.7,
+            kinetic: 0.3,
+            potential: -1.1,
+            sigma_c: 0.4,
+        }
+    }
+
+    //(virial, energy, r50, sigma_c, mass) after the scaling by (length, speed, mass)
+    fn scaled(measure: &Measure, (a, b, m): (f64, f64, f64)) -> [f64; 5] {
+        let kinetic = m * b * b * measure.kinetic;
+        let potential = m * m / a * measure.potential;
+        [
+            2. * kinetic / potential,
+            kinetic + potential,
+            a * measure.r50,
+            b * measure.sigma_c,
+            m * measure.mass,
+        ]
+    }
+
+    fn assert_close(a: f64, b: f64) {
+        assert!(
+            (a - b).abs() < 1e-12 * f64::max(1., b.abs()),
+            "{} != {}",
+            a,
+            b
+        );
+    }
+
+    #[test]
+    fn reaches_the_targets() {
+        let measure = measure();
+        let cases = [
+            Targets {
+                virial: Some(-1.),
+                energy: Some(-0.25),
+                mass: Some(1.),
+                ..targets()
+            },
+            Targets {
+                virial: Some(-0.5),
+                r50: Some(1.),
+                mass: Some(1.),
+                ..targets()
+            },
+            Targets {
+                r50: Some(2.),
+                sigma_c: Some(0.1),
+                mass: Some(3.),
+                ..targets()
+            },
+            Targets {
+                energy: Some(-0.1),
+                r50: Some(1.),
+                mass: Some(1.),
+                ..targets()
+            },
+            Targets {
+                energy: Some(-0.1),
+                sigma_c: Some(0.2),
+                ..targets()
+            },
+        ];
+        for targets in cases.iter() {
+            let values = scaled(&measure, solve(targets, &measure).unwrap());
+            let expected = [
+                targets.virial,
+                targets.energy,
+                targets.r50,
+                targets.sigma_c,
+                targets.mass,
+            ];
+            for (value, target) in values.iter().zip(expected.iter()) {
+                if let Some(target) = target {
+                    assert_close(*value, *target);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn free_scales_are_unchanged() {
+        let measure = measure();
+        let (a, b, m) = solve(
+            &Targets {
+                r50: Some(1.4),
+                ..targets()
+            },
+            &measure,
+        )
+        .unwrap();
+        assert_close(a, 2.);
+        assert_close(b, 1.);
+        assert_close(m, 1.);
+    }
+
+    #[test]
+    fn cold_initial_conditions() {
+        let (_, b, _) = solve(
+            &Targets {
+                virial: Some(0.),
+                ..targets()
+            },
+            &measure(),
+        )
+        .unwrap();
+        assert_eq!(b, 0.);
+    }
+
+    #[test]
+    fn inconsistent_targets() {
+        let measure = measure();
+        let cases = [
+            //too many targets
+            Targets {
+                virial: Some(-1.),
+                energy: Some(-0.25),
+                r50: Some(1.),
+                mass: Some(1.),
+                ..targets()
+            },
+            //cold initial conditions with a velocity dispersion
+            Targets {
+                virial: Some(0.),
+                sigma_c: Some(0.2),
+                ..targets()
+            },
+            Targets {
+                virial: Some(0.5),
+                ..targets()
+            },
+            Targets {
+                virial: Some(-1.),
+                energy: Some(0.5),
+                ..targets()
+            },
+            Targets {
+                mass: Some(-1.),
+                ..targets()
+            },
+        ];
+        for targets in cases.iter() {
+            assert!(solve(targets, &measure).is_err(), "{:?}", targets);
+        }
+    }
+}
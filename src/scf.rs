@@ -0,0 +1,331 @@
+use crate::particules::*;
+use crate::perturbation::spherical_harmonic;
+use crate::rayon::prelude::*;
+
+//self-consistent field method (Hernquist & Ostriker 1992): the density and the potential are
+//expanded on a biorthogonal basis rho_nl(r) Y_lm, phi_nl(r) Y_lm around the center of mass,
+//with real spherical harmonics (cos(m phi) for m > 0, sin(|m| phi) for m < 0)
+//the coefficients are A_nlm = sum_k m_k phi_nl(r_k) Y_lm(k) / I_nl, I_nl = int rho_nl phi_nl r^2 dr,
+//and the field is phi = sum A_nlm phi_nl(r) Y_lm, so the cost is O(N nmax lmax^2)
+//the radial functions are in units of the scale a (G = 1): phi_nl(r) = phi~_nl(r / a) / a
+
+//radial basis of the expansion, whose first term is the given model
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Basis {
+    //Hernquist (1990): phi~ = -s^l / (1 + s)^(2l+1) C_n^(2l+3/2)(xi), xi = (s - 1) / (s + 1)
+    Hernquist,
+    //Clutton-Brock (1973), Plummer:
+    //phi~ = -s^l / (1 + s^2)^(l+1/2) C_n^(l+1)(xi), xi = (s^2 - 1) / (s^2 + 1)
+    CluttonBrock,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Scf {
+    pub basis: Basis,
+    //the radial terms are n = 0..=nmax and the angular terms l = 0..=lmax
+    pub nmax: usize,
+    pub lmax: usize,
+    //scale a of the basis, None to compute it from the initial R50 of the model
+    pub scale: Option<f64>,
+}
+
+//ln Gamma(x) for x a positive multiple of 1/2
+fn ln_gamma(x: f64) -> f64 {
+    let (mut y, mut ln) = if x.fract() == 0. {
+        (1., 0.)
+    } else {
+        (0.5, 0.5 * std::f64::consts::PI.ln())
+    };
+    while y < x {
+        ln += y.ln();
+        y += 1.;
+    }
+    ln
+}
+
+//gegenbauer polynomials C_n^(alpha)(xi) for n = 0..=nmax
+fn gegenbauer(nmax: usize, alpha: f64, xi: f64, c: &mut [f64]) {
+    c[0] = 1.;
+    if nmax >= 1 {
+        c[1] = 2. * alpha * xi;
+    }
+    for n in 2..=nmax {
+        c[n] = (2. * (n as f64 + alpha - 1.) * xi * c[n - 1]
+            - (n as f64 + 2. * alpha - 2.) * c[n - 2])
+            / n as f64;
+    }
+}
+
+impl Basis {
+    //scale a of the model whose half mass radius is r50
+    pub fn scale_from_r50(&self, r50: f64) -> f64 {
+        match self {
+            Basis::Hernquist => r50 / (1. + std::f64::consts::SQRT_2),
+            Basis::CluttonBrock => r50 * (2f64.powf(2. / 3.) - 1.).sqrt(),
+        }
+    }
+
+    //index alpha of the gegenbauer polynomials of the order l
+    fn alpha(&self, l: usize) -> f64 {
+        match self {
+            Basis::Hernquist => 2. * l as f64 + 1.5,
+            Basis::CluttonBrock => l as f64 + 1.,
+        }
+    }
+
+    //I~_nl = int rho~_nl phi~_nl s^2 ds, from the orthogonality of the gegenbauer polynomials
+    //with rho~_nl = K_nl / (2 pi) s^(l-1) / (1 + s)^(2l+3) C_n (Hernquist)
+    //or K_nl / (4 pi) s^l / (1 + s^2)^(l+5/2) C_n (Clutton-Brock)
+    fn normalization(&self, n: usize, l: usize) -> f64 {
+        let alpha = self.alpha(l);
+        let (n_, l_) = (n as f64, l as f64);
+        let (k, shift) = match self {
+            Basis::Hernquist => (
+                0.5 * n_ * (n_ + 4. * l_ + 3.) + (l_ + 1.) * (2. * l_ + 1.),
+                4. * l_ + 4.,
+            ),
+            Basis::CluttonBrock => (
+                4. * n_ * (n_ + 2. * l_ + 2.) + (2. * l_ + 1.) * (2. * l_ + 3.),
+                2. * l_ + 5.,
+            ),
+        };
+        -k * (ln_gamma(n_ + 2. * alpha) - ln_gamma(n_ + 1.) - 2. * ln_gamma(alpha)
+            + (1. - 2. * alpha - shift) * std::f64::consts::LN_2)
+            .exp()
+            / (n_ + alpha)
+    }
+
+    //phi~_nl(s) in c and its derivative in d, for n = 0..=nmax at the order l
+    fn radial(&self, nmax: usize, l: usize, s: f64, c: &mut [f64], d: &mut [f64]) {
+        let alpha = self.alpha(l);
+        let l_ = l as f64;
+        //phi~ = -g(s) C_n(xi(s))
+        let (g, dg, xi, dxi) = match self {
+            Basis::Hernquist => {
+                let u = 1. + s;
+                let g = s.powi(l as i32) / u.powi(2 * l as i32 + 1);
+                let dg = if l == 0 {
+                    -g / u
+                } else {
+                    s.powi(l as i32 - 1) * (l_ * u - (2. * l_ + 1.) * s) / u.powi(2 * l as i32 + 2)
+                };
+                (g, dg, (s - 1.) / u, 2. / (u * u))
+            }
+            Basis::CluttonBrock => {
+                let u = 1. + s * s;
+                let g = s.powi(l as i32) / u.powf(l_ + 0.5);
+                let dg = if l == 0 {
+                    -s * g / u
+                } else {
+                    s.powi(l as i32 - 1) * (l_ * u - (2. * l_ + 1.) * s * s) / u.powf(l_ + 1.5)
+                };
+                (g, dg, (s * s - 1.) / u, 4. * s / (u * u))
+            }
+        };
+        gegenbauer(nmax, alpha, xi, c);
+        //d C_n^(alpha) / d xi = 2 alpha C_(n-1)^(alpha+1)
+        if nmax >= 1 {
+            gegenbauer(nmax - 1, alpha + 1., xi, d);
+        }
+        for n in (0..=nmax).rev() {
+            let dc = if n == 0 { 0. } else { 2. * alpha * d[n - 1] };
+            d[n] = -(dg * c[n] + g * dc * dxi);
+            c[n] *= -g;
+        }
+    }
+}
+
+//real spherical harmonics Y_lm and their derivatives along theta, for l = 0..=lmax,
+//index l * l + l + m (the derivative along phi is -m Y_l(-m))
+//from (1 - x^2) dP_l^m / dx = (l + m) P_(l-1)^m - l x P_l^m, with the normalizations of Y_lm:
+//sin theta dY_lm / dtheta = l cos theta Y_lm - sqrt((2l + 1) / (2l - 1) (l^2 - m^2)) Y_(l-1)m
+fn harmonics(lmax: usize, cos_theta: f64, phi: f64, y: &mut [f64], dy: &mut [f64]) {
+    let sin_theta = f64::max(1. - cos_theta * cos_theta, 1e-24).sqrt();
+    for l in 0..=lmax {
+        for m in -(l as i64)..=l as i64 {
+            let id = ((l * l + l) as i64 + m) as usize;
+            let am = m.unsigned_abs() as usize;
+            y[id] = spherical_harmonic(l, m, cos_theta, phi);
+            let lower = if am < l {
+                let factor = (2 * l + 1) as f64 / (2 * l - 1) as f64 * ((l * l - am * am) as f64);
+                factor.sqrt() * y[id - 2 * l]
+            } else {
+                0.
+            };
+            dy[id] = (l as f64 * cos_theta * y[id] - lower) / sin_theta;
+        }
+    }
+}
+
+impl Scf {
+    //index of the coefficient A_nlm, with -l <= m <= l
+    pub fn index(&self, n: usize, l: usize, m: i64) -> usize {
+        n * (self.lmax + 1) * (self.lmax + 1) + ((l * l + l) as i64 + m) as usize
+    }
+
+    //acceleration and potential of all the particules, with the expansion around their
+    //center of mass
+    //return them with the coefficients A_nlm (see index)
+    pub fn acceleration(&self, particules: &[Particule]) -> (Vec<[f64; 4]>, Vec<f64>) {
+        let (nmax, lmax) = (self.nmax, self.lmax);
+        let a = self.scale.unwrap();
+        let (center, _) = center_of_mass(particules);
+        let nb_harmonics = (lmax + 1) * (lmax + 1);
+        let nb_coefficients = (nmax + 1) * nb_harmonics;
+
+        //spherical coordinates around the center: (s = r / a, cos theta, phi, sin theta)
+        let coordinates: Vec<[f64; 4]> = particules
+            .par_iter()
+            .map(|p| {
+                let x = [
+                    p.position[0] - center[0],
+                    p.position[1] - center[1],
+                    p.position[2] - center[2],
+                ];
+                let r = f64::max((x[0] * x[0] + x[1] * x[1] + x[2] * x[2]).sqrt(), 1e-12 * a);
+                let cos_theta = x[2] / r;
+                let sin_theta = f64::max(1. - cos_theta * cos_theta, 1e-24).sqrt();
+                [r / a, cos_theta, x[1].atan2(x[0]), sin_theta]
+            })
+            .collect();
+
+        //coefficients
+        let sums = particules
+            .par_iter()
+            .zip(coordinates.par_iter())
+            .fold(
+                || vec![0f64; nb_coefficients],
+                |mut sums, (p, coordinate)| {
+                    let mut y = vec![0f64; nb_harmonics];
+                    let mut dy = vec![0f64; nb_harmonics];
+                    let mut c = vec![0f64; nmax + 1];
+                    let mut d = vec![0f64; nmax + 1];
+                    harmonics(lmax, coordinate[1], coordinate[2], &mut y, &mut dy);
+                    for l in 0..=lmax {
+                        self.basis.radial(nmax, l, coordinate[0], &mut c, &mut d);
+                        for n in 0..=nmax {
+                            for m in -(l as i64)..=l as i64 {
+                                sums[self.index(n, l, m)] +=
+                                    p.mass * c[n] * y[((l * l + l) as i64 + m) as usize];
+                            }
+                        }
+                    }
+                    sums
+                },
+            )
+            .reduce(
+                || vec![0f64; nb_coefficients],
+                |mut sums, other| {
+                    for (x, y) in sums.iter_mut().zip(other.iter()) {
+                        *x += y;
+                    }
+                    sums
+                },
+            );
+        let mut coefficients = sums;
+        for n in 0..=nmax {
+            for l in 0..=lmax {
+                let normalization = self.basis.normalization(n, l);
+                for m in -(l as i64)..=l as i64 {
+                    coefficients[self.index(n, l, m)] /= normalization;
+                }
+            }
+        }
+
+        //field: the derivatives along r, theta and phi, then in cartesian coordinates
+        let aps = coordinates
+            .par_iter()
+            .map(|coordinate| {
+                let [s, cos_theta, phi, sin_theta] = *coordinate;
+                let mut y = vec![0f64; nb_harmonics];
+                let mut dy = vec![0f64; nb_harmonics];
+                let mut c = vec![0f64; nmax + 1];
+                let mut d = vec![0f64; nmax + 1];
+                harmonics(lmax, cos_theta, phi, &mut y, &mut dy);
+                //potential and its derivatives along s, theta and phi (in units of a)
+                let (mut potential, mut d_s, mut d_theta, mut d_phi) = (0., 0., 0., 0.);
+                for l in 0..=lmax {
+                    self.basis.radial(nmax, l, s, &mut c, &mut d);
+                    for m in -(l as i64)..=l as i64 {
+                        let id = ((l * l + l) as i64 + m) as usize;
+                        //radial sums of A phi~ and A dphi~ / ds
+                        let (mut radial, mut d_radial) = (0., 0.);
+                        for n in 0..=nmax {
+                            let coefficient = coefficients[self.index(n, l, m)];
+                            radial += coefficient * c[n];
+                            d_radial += coefficient * d[n];
+                        }
+                        potential += radial * y[id];
+                        d_s += d_radial * y[id];
+                        d_theta += radial * dy[id];
+                        d_phi += if m > 0 {
+                            -(m as f64) * radial * y[id - 2 * m as usize]
+                        } else if m < 0 {
+                            -(m as f64) * radial * y[id + 2 * (-m) as usize]
+                        } else {
+                            0.
+                        };
+                    }
+                }
+                //-grad phi: a_r = -dphi / dr, a_theta = -dphi / (r dtheta),
+                //a_phi = -dphi / (r sin theta dphi)
+                let a_r = -d_s / (a * a);
+                let a_theta = -d_theta / (s * a * a);
+                let a_phi = -d_phi / (s * sin_theta * a * a);
+                let (cos_phi, sin_phi) = (phi.cos(), phi.sin());
+                [
+                    a_r * sin_theta * cos_phi + a_theta * cos_theta * cos_phi - a_phi * sin_phi,
+                    a_r * sin_theta * sin_phi + a_theta * cos_theta * sin_phi + a_phi * cos_phi,
+                    a_r * cos_theta - a_theta * sin_theta,
+                    potential / a,
+                ]
+            })
+            .collect();
+        (aps, coefficients)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plummer_is_the_first_term_of_its_expansion() {
+        let particules = generation(20000, &Model::Plummer, 42);
+        let scf = Scf {
+            basis: Basis::CluttonBrock,
+            nmax: 6,
+            lmax: 4,
+            scale: Some(1.),
+        };
+        let (aps, coefficients) = scf.acceleration(&particules);
+        //phi = -M / sqrt(1 + r^2) = A_000 phi~_00 Y_00, so A_000 = sqrt(4 pi) M, where the
+        //model is truncated at 99% of its mass M and the particules carry all of it
+        let first = coefficients[scf.index(0, 0, 0)];
+        let expected = (4. * std::f64::consts::PI).sqrt() / 0.99;
+        assert!((first - expected).abs() < 0.02 * expected);
+        for (id, coefficient) in coefficients.iter().enumerate().skip(1) {
+            assert!(
+                coefficient.abs() < 0.05 * first,
+                "A[{}] = {}",
+                id,
+                coefficient
+            );
+        }
+        let (center, _) = center_of_mass(&particules);
+        let mut errors = Vec::new();
+        for (p, ap) in particules.iter().zip(aps.iter()) {
+            let x: Vec<f64> = (0..3).map(|i| p.position[i] - center[i]).collect();
+            let r2 = x[0] * x[0] + x[1] * x[1] + x[2] * x[2];
+            if r2 > 9. {
+                continue;
+            }
+            let factor = -1. / 0.99 / (1. + r2).powf(1.5);
+            let error: f64 = (0..3).map(|i| (ap[i] - factor * x[i]).powi(2)).sum();
+            errors.push((error / (factor * factor * r2)).sqrt());
+        }
+        let mean = errors.iter().sum::<f64>() / errors.len() as f64;
+        //the higher terms mostly expand the sampling noise (0.005 with only the first term)
+        assert!(mean < 0.06);
+    }
+}
@@ -0,0 +1,273 @@
+use crate::particules::*;
+use crate::rand::Rng;
+use crate::rayon::prelude::*;
+use crate::streams::*;
+use crate::tree::*;
+
+//number of massless probes in the cavity of the shells
+const NB_PROBES: usize = 200;
+
+//spherical shell of uniform density between radius_in and radius_out
+//(thin shell if radius_in == radius_out)
+#[derive(Debug, Copy, Clone)]
+pub struct Shell {
+    pub radius_in: f64,
+    pub radius_out: f64,
+    pub mass: f64,
+}
+
+impl Shell {
+    fn is_thin(&self) -> bool {
+        self.radius_out - self.radius_in <= 1e-12 * self.radius_out
+    }
+
+    //mass inside the radius r
+    //a thin shell counts for half its mass on its surface, where the force is the mean
+    //of the forces just inside and just outside
+    fn mass(&self, r: f64) -> f64 {
+        if self.is_thin() {
+            if (r - self.radius_out).abs() <= 1e-9 * self.radius_out {
+                0.5 * self.mass
+            } else if r > self.radius_out {
+                self.mass
+            } else {
+                0.
+            }
+        } else if r <= self.radius_in {
+            0.
+        } else if r >= self.radius_out {
+            self.mass
+        } else {
+            self.mass * (r.powf(3.) - self.radius_in.powf(3.))
+                / (self.radius_out.powf(3.) - self.radius_in.powf(3.))
+        }
+    }
+
+    //potential at the radius r (G = 1)
+    fn potential(&self, r: f64) -> f64 {
+        if self.is_thin() {
+            return -self.mass / f64::max(r, self.radius_out);
+        }
+        let (a, b) = (self.radius_in, self.radius_out);
+        //uniform density
+        let rho = 3. * self.mass / (4. * std::f64::consts::PI * (b.powf(3.) - a.powf(3.)));
+        if r >= b {
+            -self.mass / r
+        } else if r <= a {
+            -2. * std::f64::consts::PI * rho * (b * b - a * a)
+        } else {
+            -self.mass(r) / r - 2. * std::f64::consts::PI * rho * (b * b - r * r)
+        }
+    }
+
+    //draw a radius in the shell
+    fn sample_radius<R: Rng>(&self, rng: &mut R) -> f64 {
+        if self.is_thin() {
+            return self.radius_out;
+        }
+        //uniform in r^3
+        let (a3, b3) = (self.radius_in.powf(3.), self.radius_out.powf(3.));
+        (a3 + (b3 - a3) * rng.gen_range(0f64, 1f64)).powf(1. / 3.)
+    }
+}
+
+//concentric shells, centered on the origin
+//used to validate the force calculation against the exact acceleration and potential
+#[derive(Debug, Clone)]
+pub struct Shells {
+    pub shells: Vec<Shell>,
+}
+
+impl Shells {
+    //shells with the radii and the masses multiplied by the factors
+    pub fn scaled(&self, length_factor: f64, mass_factor: f64) -> Shells {
+        Shells {
+            shells: self
+                .shells
+                .iter()
+                .map(|s| Shell {
+                    radius_in: length_factor * s.radius_in,
+                    radius_out: length_factor * s.radius_out,
+                    mass: mass_factor * s.mass,
+                })
+                .collect(),
+        }
+    }
+
+    pub fn enclosed_mass(&self, r: f64) -> f64 {
+        self.shells.iter().map(|s| s.mass(r)).sum()
+    }
+
+    pub fn potential(&self, r: f64) -> f64 {
+        self.shells.iter().map(|s| s.potential(r)).sum()
+    }
+
+    //radius of the empty cavity inside all the shells (0 if there is none)
+    pub fn cavity_radius(&self) -> f64 {
+        self.shells
+            .iter()
+            .map(|s| s.radius_in)
+            .fold(f64::INFINITY, f64::min)
+    }
+
+    //exact acceleration at the position x, relative to the center of the shells
+    pub fn acceleration(&self, x: &[f64; 3]) -> [f64; 3] {
+        let r = (x[0] * x[0] + x[1] * x[1] + x[2] * x[2]).sqrt();
+        if r == 0. {
+            return [0f64; 3];
+        }
+        let f = -self.enclosed_mass(r) / (r * r * r);
+        [f * x[0], f * x[1], f * x[2]]
+    }
+}
+
+//generate nb particules of the same mass on the shells
+//each particule is on a circular orbit with a random direction,
+//so the shells are in equilibrium
+pub fn shells_gen(nb: usize, shells: &Shells, seed: u64) -> Vec<Particule> {
+    let total_mass: f64 = shells.shells.iter().map(|s| s.mass).sum();
+    let mass = total_mass / nb as f64;
+    //id of the last particule (excluded) of each shell
+    let mut ends = Vec::with_capacity(shells.shells.len());
+    let mut end = 0;
+    for (i, shell) in shells.shells.iter().enumerate() {
+        end += if i == shells.shells.len() - 1 {
+            nb - end
+        } else {
+            (nb as f64 * shell.mass / total_mass).round() as usize
+        };
+        ends.push(end);
+    }
+
+    par_generation(nb, seed, |rng, id| {
+        let shell = &shells.shells[ends.iter().position(|end| id < *end).unwrap()];
+        let r = shell.sample_radius(rng);
+        //random direction of the position
+        let x1: f64 = rng.gen_range(0f64, 1f64);
+        let x2: f64 = rng.gen_range(0f64, 1f64);
+        let cos_t = 1. - 2. * x1;
+        let sin_t = (1. - cos_t * cos_t).sqrt();
+        let phi = 2. * std::f64::consts::PI * x2;
+        let n = [sin_t * phi.cos(), sin_t * phi.sin(), cos_t];
+
+        //random direction of the speed, orthogonal to the position
+        let e1 = if n[0].abs() < 0.9 {
+            [0., n[2], -n[1]]
+        } else {
+            [-n[2], 0., n[0]]
+        };
+        let norm = (e1[0] * e1[0] + e1[1] * e1[1] + e1[2] * e1[2]).sqrt();
+        let e1 = [e1[0] / norm, e1[1] / norm, e1[2] / norm];
+        let e2 = [
+            n[1] * e1[2] - n[2] * e1[1],
+            n[2] * e1[0] - n[0] * e1[2],
+            n[0] * e1[1] - n[1] * e1[0],
+        ];
+        let psi = 2. * std::f64::consts::PI * rng.gen_range(0f64, 1f64);
+        let v = (shells.enclosed_mass(r) / r).sqrt();
+
+        Particule {
+            position: [r * n[0], r * n[1], r * n[2]],
+            speed: [
+                v * (psi.cos() * e1[0] + psi.sin() * e2[0]),
+                v * (psi.cos() * e1[1] + psi.sin() * e2[1]),
+                v * (psi.cos() * e1[2] + psi.sin() * e2[2]),
+            ],
+            acceleration: [0., 0., 0.],
+            cinetic: 0f64,
+            potential: 0f64,
+            mass: mass,
+            component: Component::Cluster,
+        }
+    })
+}
+
+//return [median, 99th percentile, max] of the values
+pub fn quantiles(values: &mut [f64]) -> [f64; 3] {
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let n = values.len();
+    [values[n / 2], values[(99 * n) / 100], values[n - 1]]
+}
+
+//compare the accelerations and the potentials of the tree to the exact ones of the shells
+//the shells are centered on the origin of the model
+//return the [median, 99th percentile, max] of the relative errors of the acceleration
+//and of the potential
+pub fn shells_errors(tree: &Tree, shells: &Shells) -> ([f64; 3], [f64; 3]) {
+    let center = tree.origin;
+    let a_scale = acceleration_scale(shells);
+
+    let (mut acc_errors, mut pot_errors): (Vec<f64>, Vec<f64>) = tree
+        .particules
+        .par_iter()
+        .map(|p| {
+            let x = [
+                p.position[0] - center[0],
+                p.position[1] - center[1],
+                p.position[2] - center[2],
+            ];
+            let r = (x[0] * x[0] + x[1] * x[1] + x[2] * x[2]).sqrt();
+            let a = shells.acceleration(&x);
+            let a_norm = (a[0] * a[0] + a[1] * a[1] + a[2] * a[2]).sqrt();
+            let da = (0..3)
+                .map(|i| (p.acceleration[i] - a[i]) * (p.acceleration[i] - a[i]))
+                .sum::<f64>()
+                .sqrt();
+            let phi = shells.potential(r);
+            let acc_error = if a_norm > 0. {
+                da / a_norm
+            } else {
+                da / a_scale
+            };
+            (acc_error, ((p.potential - phi) / phi).abs())
+        })
+        .unzip();
+    (quantiles(&mut acc_errors), quantiles(&mut pot_errors))
+}
+
+//scale of the acceleration where the exact one is 0 (inside the shells)
+fn acceleration_scale(shells: &Shells) -> f64 {
+    let r_max = shells
+        .shells
+        .iter()
+        .map(|s| s.radius_out)
+        .fold(0f64, f64::max);
+    shells.enclosed_mass(2. * r_max) / (r_max * r_max)
+}
+
+//same errors for massless probes in the cavity, where the exact acceleration is 0
+//and the potential is constant (None if there is no cavity)
+//the probes are spread in radius up to 0.9 times the radius of the cavity,
+//their directions follow a Fibonacci spiral
+pub fn cavity_errors(tree: &Tree, shells: &Shells) -> Option<([f64; 3], [f64; 3])> {
+    let radius = shells.cavity_radius();
+    if radius <= 0. {
+        return None;
+    }
+    let center = tree.origin;
+    let golden_angle = std::f64::consts::PI * (3. - 5f64.sqrt());
+    let probes: Vec<[f64; 3]> = (0..NB_PROBES)
+        .map(|k| {
+            let r = 0.9 * radius * (k as f64 + 0.5) / NB_PROBES as f64;
+            let cos_t = 1. - 2. * (k as f64 + 0.5) / NB_PROBES as f64;
+            let sin_t = (1. - cos_t * cos_t).sqrt();
+            let phi = golden_angle * k as f64;
+            [
+                center[0] + r * sin_t * phi.cos(),
+                center[1] + r * sin_t * phi.sin(),
+                center[2] + r * cos_t,
+            ]
+        })
+        .collect();
+    let a_scale = acceleration_scale(shells);
+    let phi = shells.potential(0.);
+    let (mut acc_errors, mut pot_errors): (Vec<f64>, Vec<f64>) = tree
+        .probe_accelerations(&probes)
+        .iter()
+        .map(|ap| {
+            let da = (ap[0] * ap[0] + ap[1] * ap[1] + ap[2] * ap[2]).sqrt();
+            (da / a_scale, ((ap[3] - phi) / phi).abs())
+        })
+        .unzip();
+    Some((quantiles(&mut acc_errors), quantiles(&mut pot_errors)))
+}
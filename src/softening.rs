@@ -0,0 +1,165 @@
+//softening kernel of the gravitational interaction
+//the potential of a point mass m is m * phi(r) and its acceleration m * f(r) * (x_mass - x),
+//with f = phi'(r) / r, so the force and the potential are consistent
+//the Plummer, spline and Dehnen kernels have the same potential at r = 0: phi(0) = -1 / eps
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Kernel {
+    //distance clamped at epsilon: f = 1 / max(r, eps)^3 (the force is not the derivative
+    //of the potential, kept to reproduce old runs)
+    Clamp,
+    //Plummer sphere: phi = -1 / sqrt(r^2 + eps^2)
+    Plummer,
+    //cubic spline of Monaghan & Lattanzio (1985), newtonian beyond h = 2.8 eps
+    Spline,
+    //Dehnen (2001) K1, density ~ (1 - r^2 / h^2)^2, newtonian beyond h = 35 / 16 eps
+    Dehnen,
+}
+
+impl Kernel {
+    //radius beyond which the kernel is newtonian (infinite for Plummer)
+    pub fn support(&self, epsilon: f64) -> f64 {
+        match self {
+            Kernel::Clamp => epsilon,
+            Kernel::Plummer => f64::INFINITY,
+            Kernel::Spline => 2.8 * epsilon,
+            Kernel::Dehnen => 35. / 16. * epsilon,
+        }
+    }
+
+    //distance used by the higher order terms of a node at the distance r of its center of mass
+    //softened like the monopole for Plummer, None inside the support of the other kernels
+    //(the node is then a softened point mass)
+    pub fn multipole_distance(&self, r: f64, epsilon: f64) -> Option<f64> {
+        match self {
+            Kernel::Plummer => Some((r * r + epsilon * epsilon).sqrt()),
+            _ if r < self.support(epsilon) => None,
+            _ => Some(r),
+        }
+    }
+
+    //return (f, phi) at the distance r
+    pub fn kernel(&self, r: f64, epsilon: f64) -> (f64, f64) {
+        match self {
+            Kernel::Clamp => {
+                let d = f64::max(r, epsilon);
+                (1. / (d * d * d), -1. / d)
+            }
+            Kernel::Plummer => {
+                let d2 = r * r + epsilon * epsilon;
+                let d = d2.sqrt();
+                (1. / (d2 * d), -1. / d)
+            }
+            Kernel::Spline => {
+                let h = 2.8 * epsilon;
+                if r >= h {
+                    return (1. / (r * r * r), -1. / r);
+                }
+                let u = r / h;
+                let h3 = h * h * h;
+                if u < 0.5 {
+                    (
+                        (32. / 3. + u * u * (32. * u - 38.4)) / h3,
+                        (-2.8 + u * u * (16. / 3. + u * u * (6.4 * u - 9.6))) / h,
+                    )
+                } else {
+                    (
+                        (64. / 3. - 48. * u + 38.4 * u * u
+                            - 32. / 3. * u * u * u
+                            - 1. / 15. / (u * u * u))
+                            / h3,
+                        (-3.2
+                            + 1. / 15. / u
+                            + u * u * (32. / 3. + u * (-16. + u * (9.6 - 32. / 15. * u))))
+                            / h,
+                    )
+                }
+            }
+            Kernel::Dehnen => {
+                let h = 35. / 16. * epsilon;
+                if r >= h {
+                    return (1. / (r * r * r), -1. / r);
+                }
+                let x2 = r * r / (h * h);
+                (
+                    (35. / 8. - 21. / 4. * x2 + 15. / 8. * x2 * x2) / (h * h * h),
+                    -(35. - 35. * x2 + 21. * x2 * x2 - 5. * x2 * x2 * x2) / (16. * h),
+                )
+            }
+        }
+    }
+}
+
+//evolution of the softening length during the run
+#[derive(Debug, Clone, PartialEq)]
+pub enum SofteningPolicy {
+    //constant epsilon, given or computed from the initial R50
+    Fixed(Option<f64>),
+    //epsilon recomputed from the current R50 at each output
+    Adaptive,
+    //piecewise linear function of the time (in dynamical time): (t, epsilon) sorted by t
+    Scheduled(Vec<(f64, f64)>),
+    //softening of each particule from the distance to its k-th nearest neighbor,
+    //recomputed at each output (epsilon still follows R50 for the rest of the code)
+    Individual,
+}
+
+//value of the schedule at the time t, constant before the first point and after the last one
+pub fn scheduled_epsilon(schedule: &[(f64, f64)], t: f64) -> f64 {
+    let last = schedule[schedule.len() - 1];
+    if t <= schedule[0].0 {
+        return schedule[0].1;
+    }
+    if t >= last.0 {
+        return last.1;
+    }
+    let i = schedule.iter().position(|&(t_, _)| t_ > t).unwrap();
+    let (t0, e0) = schedule[i - 1];
+    let (t1, e1) = schedule[i];
+    e0 + (e1 - e0) * (t - t0) / (t1 - t0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: f64, b: f64, tolerance: f64) {
+        assert!(
+            (a - b).abs() < tolerance * f64::max(1., b.abs()),
+            "{} != {}",
+            a,
+            b
+        );
+    }
+
+    #[test]
+    fn force_is_the_derivative_of_the_potential() {
+        let epsilon = 0.1;
+        let h = 1e-6;
+        for kernel in [Kernel::Plummer, Kernel::Spline, Kernel::Dehnen].iter() {
+            //up to twice the support, the piecewise kernels are smooth at the junctions
+            for i in 1..100 {
+                let r = 0.0061 * i as f64;
+                let (f, _) = kernel.kernel(r, epsilon);
+                let derivative =
+                    (kernel.kernel(r + h, epsilon).1 - kernel.kernel(r - h, epsilon).1) / (2. * h);
+                assert_close(f * r, derivative, 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn newtonian_beyond_the_support() {
+        let epsilon = 0.1;
+        for kernel in [Kernel::Clamp, Kernel::Spline, Kernel::Dehnen].iter() {
+            let support = kernel.support(epsilon);
+            for i in 0..10 {
+                let r = support * (1. + 0.3 * i as f64);
+                let (f, phi) = kernel.kernel(r, epsilon);
+                assert_close(f, 1. / (r * r * r), 1e-12);
+                assert_close(phi, -1. / r, 1e-12);
+                assert_eq!(kernel.multipole_distance(r, epsilon), Some(r));
+            }
+            assert_eq!(kernel.multipole_distance(0.9 * support, epsilon), None);
+        }
+    }
+}
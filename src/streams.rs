@@ -0,0 +1,69 @@
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+
+use crate::rayon::prelude::*;
+
+//number of particules generated with the same random stream
+//it must not depend on the number of threads, so the particules are the same for a given seed
+pub const CHUNK_SIZE: usize = 4096;
+
+//streams of the other uses of the seed, so they are independent of the model (stream 0)
+pub const STREAM_BULGE: u64 = 1;
+pub const STREAM_HALO: u64 = 2;
+pub const STREAM_MASSES: u64 = 3;
+pub const STREAM_BINARIES: u64 = 4;
+pub const STREAM_RESAMPLING: u64 = 5;
+pub const STREAM_ORBITS: u64 = 6;
+pub const STREAM_FORCE_CHECK: u64 = 7;
+
+//seed of the stream of a use of the seed (splitmix64), the stream 0 is the seed itself
+pub fn stream_seed(seed: u64, stream: u64) -> u64 {
+    if stream == 0 {
+        return seed;
+    }
+    let mut z = seed.wrapping_add(stream.wrapping_mul(0x9e37_79b9_7f4a_7c15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+//random stream of a chunk
+//the key of the generator is made of the seed and of the index of the chunk,
+//so each chunk has its own stream, whatever the thread that computes it
+//(ChaCha20 is named, and not StdRng, so a seed gives the same streams with any version of rand)
+pub fn chunk_rng(seed: u64, chunk: usize) -> ChaCha20Rng {
+    let mut key = [0u8; 32];
+    key[..8].copy_from_slice(&seed.to_le_bytes());
+    key[8..16].copy_from_slice(&(chunk as u64).to_le_bytes());
+    ChaCha20Rng::from_seed(key)
+}
+
+//generate nb values (particules, orbits, ...) in parallel, by chunks of CHUNK_SIZE values
+//gen(rng, id) returns the value id, with the stream of its chunk
+pub fn par_generation<T, F>(nb: usize, seed: u64, gen: F) -> Vec<T>
+where
+    T: Send,
+    F: Fn(&mut ChaCha20Rng, usize) -> T + Sync,
+{
+    let nb_chunks = nb.div_ceil(CHUNK_SIZE);
+    let chunks: Vec<Vec<T>> = (0..nb_chunks)
+        .into_par_iter()
+        .map(|chunk| {
+            let mut rng = chunk_rng(seed, chunk);
+            (chunk * CHUNK_SIZE..usize::min((chunk + 1) * CHUNK_SIZE, nb))
+                .map(|id| gen(&mut rng, id))
+                .collect()
+        })
+        .collect();
+    chunks.into_iter().flatten().collect()
+}
+
+//amount ids drawn without replacement among 0..nb, in increasing order
+//each id gets a random key (in parallel, see par_generation), the smallest keys are kept
+pub fn par_sample(nb: usize, amount: usize, seed: u64) -> Vec<usize> {
+    let mut keys: Vec<(u64, usize)> = par_generation(nb, seed, |rng, id| (rng.gen::<u64>(), id));
+    keys.par_sort_unstable();
+    let mut ids: Vec<usize> = keys[..amount].iter().map(|&(_, id)| id).collect();
+    ids.par_sort_unstable();
+    ids
+}
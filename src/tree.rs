@@ -1,5 +1,131 @@
+use crate::binaries::*;
+use crate::container::*;
+use crate::direct::*;
+use crate::fmm::*;
+use crate::galaxy::*;
+use crate::multipoles::*;
 use crate::particules::*;
+use crate::perturbation::*;
+use crate::pm::*;
 use crate::rayon::prelude::*;
+use crate::resampling::*;
+use crate::scaling::*;
+use crate::scf::*;
+use crate::shells::*;
+use crate::softening::*;
+
+//number of particules up to which the potential energy of the initial conditions
+//is computed by direct summation
+const DIRECT_POTENTIAL: usize = 5000;
+//opening angle of the tree for the potential energy of the initial conditions above
+//DIRECT_POTENTIAL, with the geometric criterion (tighter than the one of the simulation)
+const POTENTIAL_THETA: f64 = 0.3;
+//maximum number of scalings of the initial conditions, and the tolerance on the factors
+const SCALING_ITERATIONS: usize = 10;
+const SCALING_TOLERANCE: f64 = 1e-9;
+
+//size of the root node, recomputed at each rebuild of the tree
+#[derive(Debug, Copy, Clone)]
+pub enum RootSize {
+    //smallest cube containing all the particules
+    BoundingBox,
+    //sphere of radius factor * R90 around the center of density,
+    //the particules outside are handled by the escaper policy
+    R90(f64),
+}
+
+//what to do with the particules outside the root node (root_size = k * R90)
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum EscaperPolicy {
+    //the root node grows to contain them
+    Keep,
+    //the unbound ones are removed from the simulation at the end of the step,
+    //the bound ones are kept
+    Remove,
+    //reflected by the wall of the sphere (its radius is fixed at the first build)
+    Reflect,
+    //moved to the other side of the center (breaks the conservation of the energy)
+    Teleport,
+}
+
+//particule removed from the simulation
+#[derive(Debug, Copy, Clone)]
+pub struct Escaper {
+    //id of the particule in the initial conditions
+    pub id: usize,
+    pub mass: f64,
+    //energy per unit mass
+    pub energy: f64,
+    pub speed: [f64; 3],
+}
+
+//method used to compute the accelerations
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Solver {
+    //walk of the octree, with the opening criterion and the multipoles of the nodes
+    Tree,
+    //direct summation over all the pairs, exact but O(N^2)
+    Direct,
+    //fast multipole method on the octree, with the order of the expansions
+    Fmm(usize),
+    //particle-mesh with n^3 cells
+    Pm(usize),
+    //particle-mesh with n^3 cells for the long range part of a gaussian split of scale r_s,
+    //and the tree for the short range part, with r_s / h (h the size of a cell)
+    TreePm(usize, f64),
+    //self-consistent field, expansion on a basis of the density and the potential
+    Scf(Scf),
+    //shell code (Henon): the force of the spherically averaged mass distribution,
+    //G M(<r) / r^2 around the center of mass
+    Spherical,
+}
+
+//criterion to use a node without opening it
+#[derive(Debug, Copy, Clone)]
+pub enum Opening {
+    //sqrt(3) * size / d < theta, d to the geometric center of the node
+    Geometric,
+    //Barnes-Hut with the offset of the center of mass (Salmon & Warren 1994):
+    //d > l / theta + delta, d to the center of mass, l the side of the node,
+    //delta the distance between the center of mass and the geometric center
+    BarnesHut,
+    //relative acceleration (GADGET-2): M l^2 / d^4 < alpha |a|, a the previous acceleration
+    //of the particule (Barnes-Hut is used when there is no previous acceleration)
+    Relative(f64),
+}
+
+//parameters of the simulation read from the configuration file, to build the tree
+pub struct Parameters {
+    //number of particules generated and saved
+    pub nb: usize,
+    pub nb_save: usize,
+    pub mu: f64,
+    pub lambda: f64,
+    pub targets: Targets,
+    pub theta: f64,
+    pub model: Model,
+    pub seed: u64,
+    pub nb_bins: usize,
+    pub nb_neighbors: usize,
+    //mu and theta of the first steps
+    pub mu_init: f64,
+    pub theta_init: f64,
+    //initial conditions moved to the barycentric frame
+    pub barycentric: bool,
+    pub keep_centered: bool,
+    pub restrict: Option<Component>,
+    pub root_size: RootSize,
+    pub escaper_policy: EscaperPolicy,
+    pub container_radius: Option<f64>,
+    pub multipole_order: usize,
+    pub opening: Opening,
+    pub kernel: Kernel,
+    pub softening: SofteningPolicy,
+    pub solver: Solver,
+    pub resampling: Option<Resampling>,
+    pub perturbation: Option<Perturbation>,
+    pub binaries: Option<Binaries>,
+}
 
 pub struct Node {
     // 1/2 of the side of the box
@@ -11,6 +137,8 @@ pub struct Node {
     pub particule: Option<u32>,
     //vector with the id of the sub-nodes or None if the sub-node doesn't exist
     pub kids: [Option<u32>; 8],
+    //maximum softening of the particules of the node (individual softenings only)
+    pub softening: f64,
 }
 
 impl Node {
@@ -46,6 +174,84 @@ pub struct Tree {
     pub nb_neighbors: usize,
     pub mu_init: f64,
     pub theta_init: f64,
+    //move the particules back to the barycentric frame during the simulation
+    pub keep_centered: bool,
+    //initial position of the center of mass
+    pub com_init: [f64; 3],
+    //position removed from the particules to keep them centered
+    pub shift: [f64; 3],
+    //displacement of the center of mass since the start of the simulation
+    pub drift: [f64; 3],
+    //values of the initial conditions after the scaling
+    pub scaling: Scaling,
+    //origin of the model, moved like the particules by the recentering and the scaling
+    pub origin: [f64; 3],
+    //analytic halo potential, centered on the origin of the model
+    pub halo: Option<Hernquist>,
+    //if set, the inertia matrix and the density are computed for this component only
+    pub restrict: Option<Component>,
+    //catalogue of the primordial binaries
+    pub binaries: Vec<Binary>,
+    //shells with an exact acceleration, to measure the error of the forces
+    pub shells: Option<Shells>,
+    //perturbation imposed on the initial conditions, projected on each snapshot
+    pub perturbation: Option<Perturbation>,
+    //seed of the random streams of the run (initial conditions and force checks)
+    pub seed: u64,
+    pub root_size: RootSize,
+    pub escaper_policy: EscaperPolicy,
+    //radius of the reflecting wall, k * R90 at the first build (reflect policy only)
+    pub reflect_radius: Option<f64>,
+    //number of particules teleported because they were outside the root node
+    pub nb_teleported: usize,
+    //ids of the particules in the initial conditions (they change when particules are removed)
+    pub ids: Vec<usize>,
+    //particules removed since the last time the vector was emptied
+    pub escapers: Vec<Escaper>,
+    //total mass and energy of the removed particules
+    pub removed_mass: f64,
+    pub removed_energy: f64,
+    //reflecting spherical wall around the origin
+    pub container: Option<Container>,
+    //virial ratio with the pressure of the wall: (2 e_c - 3 P V) / e_p
+    pub wall_virial: f64,
+    //order of the multipole expansion of the nodes: 1 monopole, 2 quadrupole, 3 octupole
+    pub multipole_order: usize,
+    //second and third moments of the nodes (empty if not used by the expansion)
+    pub quadrupoles: Vec<[f64; 6]>,
+    pub octupoles: Vec<[f64; 10]>,
+    pub opening: Opening,
+    //softening kernel of the interactions
+    pub kernel: Kernel,
+    //evolution of epsilon during the run
+    pub softening: SofteningPolicy,
+    //method used for the accelerations (the tree is always built, for the densities)
+    pub solver: Solver,
+    //mean number of interactions per particule of the last fast multipole computation
+    pub fmm_interactions: f64,
+    //scale r_s of the gaussian split of the TreePM solver (the tree computes the short range part)
+    pub split: Option<f64>,
+    //softening of each particule, empty if all the particules use epsilon
+    pub softenings: Vec<f64>,
+    //coefficients of the last expansion of the SCF solver (see Scf::index)
+    pub scf_coefficients: Vec<f64>,
+}
+
+//values of the tree that change during the simulation, saved to restore them after a trial run
+//(the other values are computed again from the particules)
+pub struct State {
+    particules: Vec<Particule>,
+    ids: Vec<usize>,
+    binaries: Vec<Binary>,
+    escapers: Vec<Escaper>,
+    removed_mass: f64,
+    removed_energy: f64,
+    nb_teleported: usize,
+    container: Option<Container>,
+    wall_virial: f64,
+    shift: [f64; 3],
+    drift: [f64; 3],
+    origin: [f64; 3],
 }
 
 impl Tree {
@@ -99,6 +305,7 @@ impl Tree {
             mass: 0.,
             particule: None,
             kids: [None; 8],
+            softening: 0.,
         });
         self.nodes[mother_id].kids[subtree] = Some(last_node as u32);
     }
@@ -138,48 +345,250 @@ impl Tree {
         }
     }
 
-    //add a particule to the root of the tree
-    //check if the particules is out of simulation then add it recursively to the tree
-    //the particule is out of simulation of the distance to the center of density
-    //is greater than the size of the root node
-    //(so the simulation boundary is actually a sphere not a box!)
-    fn add_particule(&mut self, particule_id: usize) {
-        let p = &mut self.particules[particule_id];
-        let d: f64 = p
-            .position
+    //apply the escaper policy to the particules farther than radius from the center
+    //return the size of the root node needed to contain the remaining particules
+    fn handle_escapers(&mut self, center: [f64; 3], radius: f64) -> f64 {
+        let distance = |p: &Particule| {
+            p.position
+                .iter()
+                .zip(center.iter())
+                .map(|(x, c)| (*x - *c) * (*x - *c))
+                .sum::<f64>()
+                .sqrt()
+        };
+        match self.escaper_policy {
+            //the unbound particules are removed at the end of the step (see remove_escapers)
+            EscaperPolicy::Keep | EscaperPolicy::Remove => (),
+            EscaperPolicy::Reflect => {
+                //the wall does not follow R90, its radius is the one of the first build
+                let radius = *self.reflect_radius.get_or_insert(radius);
+                self.particules.iter_mut().for_each(|p| {
+                    let d = distance(p);
+                    if d > radius {
+                        //mirror image inside the sphere, the radial speed points inward
+                        let n = [
+                            (p.position[0] - center[0]) / d,
+                            (p.position[1] - center[1]) / d,
+                            (p.position[2] - center[2]) / d,
+                        ];
+                        let v_r = (0..3).map(|i| p.speed[i] * n[i]).sum::<f64>();
+                        for i in 0..3 {
+                            p.position[i] = center[i] + (2. * radius - d) * n[i];
+                            if v_r > 0. {
+                                p.speed[i] -= 2. * v_r * n[i];
+                            }
+                        }
+                    }
+                });
+            }
+            EscaperPolicy::Teleport => {
+                let mut nb_teleported = 0;
+                self.particules.iter_mut().for_each(|p| {
+                    if distance(p) > radius {
+                        //teleport the particule to the other side
+                        p.position
+                            .iter_mut()
+                            .zip(center.iter())
+                            .for_each(|(x, c)| *x = -0.95 * (*x) + 2. * c);
+                        nb_teleported += 1;
+                    }
+                });
+                self.nb_teleported += nb_teleported;
+            }
+        }
+        self.particules.iter().map(distance).fold(radius, f64::max)
+    }
+
+    //remove the unbound particules farther than the radius of the root node from the center,
+    //with the potential and the speed of the end of the step
+    //the energy of the pairs of removed particules is counted once in removed_energy
+    fn remove_escapers(&mut self) {
+        let (center, radius) = self.root(self.root_size);
+        let halo = self.halo;
+        let origin = self.origin;
+        let mut escapers = Vec::new();
+        for (id, p) in self.particules.iter().enumerate() {
+            let d = (0..3)
+                .map(|i| (p.position[i] - center[i]).powf(2.))
+                .sum::<f64>()
+                .sqrt();
+            if d <= radius {
+                continue;
+            }
+            let mut energy = 0.5 * p.speed.iter().map(|s| s * s).sum::<f64>() + p.potential;
+            if let Some(halo) = halo {
+                let r = (0..3)
+                    .map(|i| (p.position[i] - origin[i]).powf(2.))
+                    .sum::<f64>()
+                    .sqrt();
+                energy += halo.potential(r);
+            }
+            if energy > 0. {
+                escapers.push((id, energy));
+            }
+        }
+        if escapers.is_empty() {
+            return;
+        }
+        let mut kept = vec![true; self.particules.len()];
+        for (k, &(id, energy)) in escapers.iter().enumerate() {
+            let p = &self.particules[id];
+            kept[id] = false;
+            self.removed_mass += p.mass;
+            self.removed_energy += p.mass * energy;
+            for &(q_id, _) in escapers[k + 1..].iter() {
+                let q = &self.particules[q_id];
+                let d = (0..3)
+                    .map(|i| (p.position[i] - q.position[i]).powf(2.))
+                    .sum::<f64>()
+                    .sqrt();
+                let epsilon = if self.softenings.is_empty() {
+                    self.epsilon
+                } else {
+                    f64::max(self.softenings[id], self.softenings[q_id])
+                };
+                self.removed_energy -= p.mass * q.mass * self.kernel.kernel(d, epsilon).1;
+            }
+            self.escapers.push(Escaper {
+                id: self.ids[id],
+                mass: p.mass,
+                energy: energy,
+                speed: p.speed,
+            });
+        }
+        self.remove_particules(&kept);
+        self.rebuild_tree();
+        self.compute_acceleration();
+    }
+
+    //remove the particules that are not kept
+    //the ids of the binaries are updated, the binaries with a removed star are forgotten
+    fn remove_particules(&mut self, kept: &[bool]) {
+        let mut new_ids = vec![None; kept.len()];
+        let mut nb = 0;
+        for (id, k) in kept.iter().enumerate() {
+            if *k {
+                new_ids[id] = Some(nb);
+                nb += 1;
+            }
+        }
+        let mut k = kept.iter();
+        self.particules.retain(|_| *k.next().unwrap());
+        let mut k = kept.iter();
+        self.ids.retain(|_| *k.next().unwrap());
+        if !self.softenings.is_empty() {
+            let mut k = kept.iter();
+            self.softenings.retain(|_| *k.next().unwrap());
+        }
+        self.binaries = self
+            .binaries
             .iter()
-            .zip(self.center.iter())
-            .map(|(x, c)| (*x - *c) * (*x - *c))
-            .sum::<f64>()
-            .sqrt();
-        if d > self.nodes[0].size {
-            //println!("particule escaped simulation");
+            .filter_map(|b| match (new_ids[b.ids[0]], new_ids[b.ids[1]]) {
+                (Some(id1), Some(id2)) => Some(Binary {
+                    ids: [id1, id2],
+                    ..*b
+                }),
+                _ => None,
+            })
+            .collect();
+    }
+
+    //center and size of the root node
+    fn root(&self, root_size: RootSize) -> ([f64; 3], f64) {
+        match root_size {
+            RootSize::BoundingBox => {
+                let mut min = [f64::INFINITY; 3];
+                let mut max = [f64::NEG_INFINITY; 3];
+                for p in self.particules.iter() {
+                    for i in 0..3 {
+                        min[i] = f64::min(min[i], p.position[i]);
+                        max[i] = f64::max(max[i], p.position[i]);
+                    }
+                }
+                let center = [
+                    0.5 * (min[0] + max[0]),
+                    0.5 * (min[1] + max[1]),
+                    0.5 * (min[2] + max[2]),
+                ];
+                let size = (0..3).map(|i| 0.5 * (max[i] - min[i])).fold(0f64, f64::max);
+                //a small margin, so the particules on the faces are inside
+                (center, f64::max(size * (1. + 1e-9), 1e-12))
+            }
+            RootSize::R90(factor) => (self.center, factor * self.rayons[2]),
+        }
+    }
+
+    //build the tree from scratch, with the root node given by root_size
+    //with a root node of a multiple of R90, the escaper policy is applied first
+    //(so the simulation boundary is actually a sphere not a box!)
+    fn build(&mut self, root_size: RootSize) {
+        let (center, mut size) = self.root(root_size);
+        if let RootSize::R90(_) = root_size {
+            size = self.handle_escapers(center, size);
+        }
+        self.nodes.clear();
+        self.nodes.push(Node {
+            size: size,
+            center: center,
+            center_of_mass: [0., 0., 0.],
+            mass: 0.,
+            particule: None,
+            kids: [None; 8],
+            softening: 0.,
+        });
+
+        for p_id in 0..self.particules.len() {
+            self.add_particule_rec(0, p_id);
+        }
+        self.quadrupoles.clear();
+        self.octupoles.clear();
+        if self.multipole_order >= 2 {
+            self.quadrupoles.resize(self.nodes.len(), [0f64; 6]);
+        }
+        if self.multipole_order >= 3 {
+            self.octupoles.resize(self.nodes.len(), [0f64; 10]);
+        }
+        self.compute_center_of_mass(0);
+        if !self.softenings.is_empty() {
+            self.compute_max_softening(0);
+        }
+    }
+
+    pub fn new_tree(parameters: Parameters) -> Tree {
+        let Parameters {
+            nb,
+            nb_save,
+            mu,
+            lambda,
+            targets,
+            theta,
+            model,
+            seed,
+            nb_bins,
+            nb_neighbors,
+            mu_init,
+            theta_init,
+            barycentric,
+            keep_centered,
+            restrict,
+            root_size,
+            escaper_policy,
+            container_radius,
+            multipole_order,
+            opening,
+            kernel,
+            softening,
+            solver,
+            resampling,
+            perturbation,
+            binaries,
+        } = parameters;
+        let particules = generation(nb, &model, seed);
 
-            //teleport the particule to the other side
-            p.position
-                .iter_mut()
-                .zip(self.center.iter())
-                .for_each(|(x, c)| *x = -0.95 * (*x) + 2.*c);
-        }
-        self.add_particule_rec(0, particule_id);
-    }
-
-    pub fn new_tree(
-        nb: usize,
-        nb_save: usize,
-        mu: f64,
-        lambda: f64,
-        virial: f64,
-        theta: f64,
-        is_plummer: bool,
-        from_csv: bool,
-        nb_bins: usize,
-        nb_neighbors: usize,
-        mu_init: f64,
-        theta_init: f64,
-    ) -> Tree {
         let mut tree = Tree {
-            particules: generation(nb, is_plummer, from_csv),
+            //values without targets, the potential energy is set from the first accelerations
+            scaling: unscaled(&particules, 0.),
+            particules: particules,
             nodes: Vec::new(),
             center: [0f64, 0f64, 0f64],
             rayons: [0f64, 0f64, 0f64],
@@ -197,65 +606,343 @@ impl Tree {
             nb_neighbors: nb_neighbors,
             mu_init: mu_init,
             theta_init: theta_init,
+            keep_centered: keep_centered,
+            com_init: [0f64; 3],
+            shift: [0f64; 3],
+            drift: [0f64; 3],
+            //origin of the model, where the analytic halo and the shells are centered
+            origin: [0f64; 3],
+            halo: None,
+            restrict: restrict,
+            binaries: Vec::new(),
+            shells: None,
+            perturbation: perturbation,
+            seed: seed,
+            root_size: root_size,
+            escaper_policy: escaper_policy,
+            reflect_radius: None,
+            nb_teleported: 0,
+            ids: Vec::new(),
+            escapers: Vec::new(),
+            removed_mass: 0.,
+            removed_energy: 0.,
+            container: container_radius.map(Container::new),
+            wall_virial: 0f64,
+            multipole_order: multipole_order,
+            quadrupoles: Vec::new(),
+            octupoles: Vec::new(),
+            opening: opening,
+            kernel: kernel,
+            softening: softening,
+            softenings: Vec::new(),
+            solver: solver,
+            fmm_interactions: 0.,
+            split: None,
+            scf_coefficients: Vec::new(),
         };
-        //root node
-        tree.nodes.push(Node {
-            //fixed root node size ...
-            size: 40.,
-            center: [0., 0., 0.],
-            center_of_mass: [0., 0., 0.],
-            mass: 0.,
-            particule: None,
-            kids: [None; 8],
-        });
+        //change the resolution of the initial conditions
+        if let Some(resampling) = resampling {
+            tree.particules = match resampling {
+                Resampling::Downsample(fraction) => downsample(&tree.particules, fraction, seed),
+                Resampling::Upsample { children, jitter } => {
+                    let neighbors = tree.nearest_neighbors(nb_neighbors);
+                    upsample(&tree.particules, &neighbors, children, jitter, seed)
+                }
+            };
+            println!("resampling : {} particules", tree.particules.len());
+        }
+        //remove the net momentum and center of mass offset of the initial conditions
+        //(the origin of the model follows the particules)
+        if barycentric || keep_centered {
+            let (position, _) = recenter(&mut tree.particules);
+            for i in 0..3 {
+                tree.origin[i] -= position[i];
+            }
+        }
+        let (com_init, _) = center_of_mass(&tree.particules);
+        tree.com_init = com_init;
+        //scale the initial conditions to reach the targets (virial ratio, energy, ...)
+        //(the scaling is done around the center of mass)
+        //the potential energy is softened like in the simulation, and a fixed or scheduled
+        //epsilon doesn't follow the length factor, so the scaling is repeated until it converges
+        if !targets.is_empty() {
+            let mut factors = [1f64; 3];
+            for _ in 0..SCALING_ITERATIONS {
+                let potential = tree.potential_energy();
+                let scaling = scale(&mut tree.particules, &targets, potential)
+                    .unwrap_or_else(|e| panic!("inconsistent scaling targets : {}", e));
+                for i in 0..3 {
+                    tree.origin[i] =
+                        com_init[i] + scaling.length_factor * (tree.origin[i] - com_init[i]);
+                }
+                factors[0] *= scaling.mass_factor;
+                factors[1] *= scaling.length_factor;
+                factors[2] *= scaling.speed_factor;
+                tree.scaling = Scaling {
+                    mass_factor: factors[0],
+                    length_factor: factors[1],
+                    speed_factor: factors[2],
+                    ..scaling
+                };
+                //(the speed factor stays 0 for cold initial conditions)
+                if (scaling.mass_factor - 1.).abs() < SCALING_TOLERANCE
+                    && (scaling.length_factor - 1.).abs() < SCALING_TOLERANCE
+                    && (scaling.speed_factor == 0.
+                        || (scaling.speed_factor - 1.).abs() < SCALING_TOLERANCE)
+                {
+                    break;
+                }
+            }
+        }
+        let scaling = tree.scaling;
+        tree.halo = match &model {
+            Model::Galaxy(galaxy) if !galaxy.halo_live && galaxy.halo.mass > 0. => {
+                Some(Hernquist {
+                    mass: scaling.mass_factor * galaxy.halo.mass,
+                    scale: scaling.length_factor * galaxy.halo.scale,
+                })
+            }
+            _ => None,
+        };
+        tree.shells = match &model {
+            Model::Shells(shells) => {
+                Some(shells.scaled(scaling.length_factor, scaling.mass_factor))
+            }
+            _ => None,
+        };
+        //perturbation of the equilibrium, around the origin of the model
+        if let Some(perturbation) = tree.perturbation {
+            perturb(&mut tree, &perturbation);
+        }
+        //replace some particules by primordial binaries, after the scaling so it doesn't change their orbits
+        if let Some(binaries) = binaries {
+            tree.binaries = make_binaries(&mut tree.particules, &binaries, seed)
+                .unwrap_or_else(|e| panic!("primordial binaries : {}", e));
+        }
+        tree.ids = (0..tree.particules.len()).collect();
 
-        for p_id in 0..tree.particules.len() {
-            tree.add_particule(p_id);
+        if let Some(container) = &tree.container {
+            let nb_outside = tree
+                .particules
+                .iter()
+                .filter(|p| {
+                    (0..3)
+                        .map(|i| (p.position[i] - tree.origin[i]).powf(2.))
+                        .sum::<f64>()
+                        > container.radius * container.radius
+                })
+                .count();
+            if nb_outside > 0 {
+                panic!(
+                    "{} particules are outside the container of radius {}",
+                    nb_outside, container.radius
+                );
+            }
         }
-        tree.compute_center_of_mass(0);
+        //first tree around all the particules, to find the center of density and R90
+        tree.build(RootSize::BoundingBox);
         tree.compute_center();
         tree.compute_rayons();
-        tree.compute_acceleration();
-        tree.compute_energy();
-        tree.compute_epsilon();
-        tree.compute_dt();
-
-        let virial_temp = tree.virial;
-        //println!("energy{}", tree.energy);
-        //println!("virial temp {}", virial_temp);
 
-        //change the virial ratio
-        tree.particules.par_iter_mut().for_each(|p| {
-            p.speed
-                .iter_mut()
-                .for_each(|s| *s *= (virial / virial_temp).sqrt());
-        });
         tree.rebuild_tree();
         tree.compute_center();
         tree.compute_rayons();
+        //the fixed epsilon is computed once, from the initial R50
+        if tree.softening == SofteningPolicy::Fixed(None) {
+            tree.softening = SofteningPolicy::Fixed(Some(tree.r50_epsilon()));
+        }
+        //the scale of the SCF basis is computed once, from the initial R50
+        if let Solver::Scf(mut scf) = tree.solver {
+            if scf.scale.is_none() {
+                scf.scale = Some(scf.basis.scale_from_r50(tree.rayons[1]));
+                tree.solver = Solver::Scf(scf);
+            }
+        }
+        //epsilon before the first acceleration, so the initial energy uses the same softening
+        tree.compute_epsilon(0.);
+        //the light bodies of a planetary system are on keplerian orbits around the central body,
+        //the softening must not change its force at their pericenters (epsilon << a_min)
+        if let Model::Planetary(_) = model {
+            if let Some(c_id) = tree
+                .particules
+                .iter()
+                .position(|p| p.component == Component::Central)
+            {
+                let central = tree.particules[c_id];
+                let error = tree
+                    .particules
+                    .iter()
+                    .enumerate()
+                    .filter(|(id, _)| *id != c_id)
+                    .map(|(id, p)| {
+                        let (a, e) = orbital_elements(&central, p);
+                        let pericenter = f64::max(a * (1. - e), 0.);
+                        let epsilon = if tree.softenings.is_empty() {
+                            tree.epsilon
+                        } else {
+                            f64::max(tree.softenings[c_id], tree.softenings[id])
+                        };
+                        let (f, _) = tree.kernel.kernel(pericenter, epsilon);
+                        1. - f * pericenter.powi(3)
+                    })
+                    .fold(0f64, f64::max);
+                if error > 1e-3 {
+                    println!(
+                        "WARNING! : the softening changes the force of the central body by up to {} at the pericenters (epsilon = {} should be << a_min)",
+                        error, tree.epsilon
+                    );
+                }
+            }
+        }
+        //the binaries tighter than epsilon are softened, their orbits are not keplerian
+        if let Some(binaries) = binaries {
+            let nb_softened = tree
+                .binaries
+                .iter()
+                .filter(|b| b.semi_major_axis < tree.epsilon)
+                .count();
+            if binaries.a_max < tree.epsilon {
+                println!(
+                    "WARNING! : binary_a_max = {} is below epsilon = {}, all the binaries are softened",
+                    binaries.a_max, tree.epsilon
+                );
+            } else if nb_softened > 0 {
+                println!(
+                    "WARNING! : {} binaries have a semi-major axis below epsilon = {}, they are softened",
+                    nb_softened, tree.epsilon
+                );
+            }
+        }
         tree.compute_acceleration();
+        //the relative criterion needs an acceleration, the first one is from Barnes-Hut
+        if let Opening::Relative(_) = tree.opening {
+            tree.compute_acceleration();
+        }
+        if tree.escaper_policy == EscaperPolicy::Remove {
+            tree.remove_escapers();
+        }
+        if targets.is_empty() {
+            let potential = tree
+                .particules
+                .iter()
+                .map(|p| 0.5 * p.mass * p.potential)
+                .sum();
+            tree.scaling = unscaled(&tree.particules, potential);
+        }
         tree.compute_energy();
-        tree.compute_epsilon();
         tree.compute_dt();
         tree
     }
 
-    //rebuild the tree after the particules moved
-    fn rebuild_tree(&mut self) {
-        self.nodes.clear();
-        self.nodes.push(Node {
-            size: 40.,
-            center: self.center,
-            center_of_mass: [0., 0., 0.],
-            mass: 0.,
-            particule: None,
-            kids: [None; 8],
-        });
+    //potential energy of the particules (without the analytic halo), with the softening
+    //the simulation would use for these particules, so the scaling reaches the reported virial
+    //by direct summation for small N and with the tree at POTENTIAL_THETA otherwise
+    //the tree is built around all the particules, it must be built again after
+    pub fn potential_energy(&mut self) -> f64 {
+        self.softenings.clear();
+        self.build(RootSize::BoundingBox);
+        self.compute_center();
+        self.compute_rayons();
+        self.compute_epsilon(0.);
+        let potential = if self.particules.len() <= DIRECT_POTENTIAL {
+            direct_acceleration(
+                &self.particules,
+                self.kernel,
+                self.epsilon,
+                &self.softenings,
+            )
+            .iter()
+            .zip(self.particules.iter())
+            .map(|(ap, p)| 0.5 * p.mass * ap[3])
+            .sum()
+        } else {
+            let (theta, opening) = (self.theta, self.opening);
+            self.theta = POTENTIAL_THETA;
+            self.opening = Opening::Geometric;
+            let potentials: Vec<f64> = (0..self.particules.len())
+                .into_par_iter()
+                .map(|p_id| 0.5 * self.particules[p_id].mass * self.tree_acceleration(p_id).0[3])
+                .collect();
+            self.theta = theta;
+            self.opening = opening;
+            potentials.iter().sum()
+        };
+        //the individual softenings are computed again for the final particules
+        self.softenings.clear();
+        potential
+    }
 
-        for p_id in 0..self.particules.len() {
-            self.add_particule(p_id);
+    //save the values that change during the simulation
+    pub fn save(&self) -> State {
+        State {
+            particules: self.particules.clone(),
+            ids: self.ids.clone(),
+            binaries: self.binaries.clone(),
+            escapers: self.escapers.clone(),
+            removed_mass: self.removed_mass,
+            removed_energy: self.removed_energy,
+            nb_teleported: self.nb_teleported,
+            container: self.container,
+            wall_virial: self.wall_virial,
+            shift: self.shift,
+            drift: self.drift,
+            origin: self.origin,
         }
-        self.compute_center_of_mass(0);
+    }
+
+    //go back to a saved state and compute again the tree, the acceleration and the other values
+    //(the particules removed since the save are back, with their ids and their binaries)
+    pub fn restore(&mut self, state: State) {
+        self.particules = state.particules;
+        self.ids = state.ids;
+        self.binaries = state.binaries;
+        self.escapers = state.escapers;
+        self.removed_mass = state.removed_mass;
+        self.removed_energy = state.removed_energy;
+        self.nb_teleported = state.nb_teleported;
+        self.container = state.container;
+        self.wall_virial = state.wall_virial;
+        self.shift = state.shift;
+        self.drift = state.drift;
+        self.origin = state.origin;
+        //as in new_tree, R90 is found before the first rebuild (the number of particules
+        //can be different, the individual softenings are computed again)
+        self.softenings.clear();
+        self.build(RootSize::BoundingBox);
+        self.compute_center();
+        self.compute_rayons();
+        self.rebuild_tree();
+        self.compute_center();
+        self.compute_rayons();
+        self.compute_epsilon(0.);
+        self.compute_acceleration();
+        self.compute_energy();
+        self.compute_dt();
+    }
+
+    //update the drift of the center of mass since the start of the simulation
+    //if keep_centered, the particules are moved back to the barycentric frame
+    pub fn compute_drift(&mut self) {
+        if self.keep_centered {
+            let (position, _) = recenter(&mut self.particules);
+            for i in 0..3 {
+                self.center[i] -= position[i];
+                self.origin[i] -= position[i];
+                self.shift[i] += position[i];
+            }
+            self.rebuild_tree();
+            self.drift = self.shift;
+        } else {
+            let (position, _) = center_of_mass(&self.particules);
+            for i in 0..3 {
+                self.drift[i] = position[i] - self.com_init[i];
+            }
+        }
+    }
+
+    //rebuild the tree after the particules moved
+    fn rebuild_tree(&mut self) {
+        self.build(self.root_size);
     }
 
     //recursively change the center of mass of the nodes
@@ -280,32 +967,147 @@ impl Tree {
                 }
             }
         }
+
+        //moments of the node around its center of mass, from the moments of the kids
+        if self.multipole_order >= 2 {
+            let mut quadrupole = [0f64; 6];
+            let mut octupole = [0f64; 10];
+            let use_octupole = self.multipole_order >= 3;
+            for kid_id in kids.iter().filter_map(|k| *k) {
+                let kid = &self.nodes[kid_id as usize];
+                let s = [
+                    kid.center_of_mass[0] - self.nodes[id].center_of_mass[0],
+                    kid.center_of_mass[1] - self.nodes[id].center_of_mass[1],
+                    kid.center_of_mass[2] - self.nodes[id].center_of_mass[2],
+                ];
+                add_shifted_moments(
+                    &mut quadrupole,
+                    if use_octupole {
+                        Some(&mut octupole)
+                    } else {
+                        None
+                    },
+                    kid.mass,
+                    &s,
+                    &self.quadrupoles[kid_id as usize],
+                    self.octupoles.get(kid_id as usize),
+                );
+            }
+            self.quadrupoles[id] = quadrupole;
+            if use_octupole {
+                self.octupoles[id] = octupole;
+            }
+        }
+    }
+
+    //recursively compute the maximum softening of the nodes
+    fn compute_max_softening(&mut self, id: usize) {
+        let kids = self.nodes[id].kids;
+        let mut softening = match self.nodes[id].particule {
+            Some(p_id) => self.softenings[p_id as usize],
+            None => 0.,
+        };
+        for kid_id in kids.iter().filter_map(|k| *k) {
+            self.compute_max_softening(kid_id as usize);
+            softening = f64::max(softening, self.nodes[kid_id as usize].softening);
+        }
+        self.nodes[id].softening = softening;
+    }
+
+    //softening of the interaction between the particule p_id and the node n
+    //the pairs use the largest of the two softenings, so the interaction is symmetric
+    //(a probe, without id, has the softening epsilon)
+    fn pair_epsilon(&self, p_id: Option<usize>, n: &Node) -> f64 {
+        match p_id {
+            Some(p_id) if !self.softenings.is_empty() => {
+                f64::max(self.softenings[p_id], n.softening)
+            }
+            _ if !self.softenings.is_empty() => f64::max(self.epsilon, n.softening),
+            _ => self.epsilon,
+        }
+    }
+
+    //true if the node can be used for the particule without opening it
+    fn accept_node(&self, p: &Particule, n: &Node, epsilon: f64) -> bool {
+        //a particule inside the node (with a margin of 20%) always opens it
+        if (0..3).all(|i| (p.position[i] - n.center[i]).abs() < 1.2 * n.size) {
+            return false;
+        }
+        let distance = |x: &[f64; 3]| {
+            p.position
+                .iter()
+                .zip(x.iter())
+                .map(|(a, b)| (a - b) * (a - b))
+                .sum::<f64>()
+                .sqrt()
+        };
+        //with individual softenings, a particule in the softened region of a node opens it
+        let support = self.kernel.support(epsilon);
+        if !self.softenings.is_empty()
+            && support.is_finite()
+            && distance(&n.center_of_mass) < support
+        {
+            return false;
+        }
+        let barnes_hut = || {
+            let delta = n
+                .center
+                .iter()
+                .zip(n.center_of_mass.iter())
+                .map(|(a, b)| (a - b) * (a - b))
+                .sum::<f64>()
+                .sqrt();
+            distance(&n.center_of_mass) > 2. * n.size / self.theta + delta
+        };
+        match self.opening {
+            Opening::Geometric => {
+                f64::sqrt(3f64) * n.size / f64::max(distance(&n.center), epsilon) < self.theta
+            }
+            Opening::BarnesHut => barnes_hut(),
+            Opening::Relative(alpha) => {
+                let a = p.acceleration.iter().map(|a| a * a).sum::<f64>().sqrt();
+                if a == 0. {
+                    return barnes_hut();
+                }
+                let d = distance(&n.center_of_mass);
+                let l = 2. * n.size;
+                n.mass * l * l < alpha * a * d * d * d * d
+            }
+        }
     }
 
-    //Compute the acceleration on p_id by walking the tree recursively,
-    //and using the parameter theta to approximate long range interaction
+    //Compute the acceleration on p (the particule p_id, or a probe without id)
+    //by walking the tree recursively, and using the parameter theta to approximate long range interaction
     //the acceleration and potential is incremented in the array ap
-    fn compute_acceleration_rec(&self, p_id: usize, node_id: usize) -> [f64; 4] {
-        let p = &self.particules[p_id];
+    //interactions counts the nodes and particules used
+    fn compute_acceleration_rec(
+        &self,
+        p: &Particule,
+        p_id: Option<usize>,
+        node_id: usize,
+        interactions: &mut usize,
+    ) -> [f64; 4] {
         let n = &self.nodes[node_id];
         //acceleration : (ap[0],ap[1],ap[2])
         //potential : ap[3]
         let mut ap = [0f64; 4];
 
-        if n.particule.is_some() && n.particule.unwrap() == p_id as u32 {
+        if n.particule.is_some() && n.particule.map(|id| id as usize) == p_id {
             return ap;
         }
+        //the short range part of the gaussian split is neglected beyond CUT * r_s
+        if let Some(r_s) = self.split {
+            let d2 = (0..3)
+                .map(|i| f64::max((p.position[i] - n.center[i]).abs() - n.size, 0.).powi(2))
+                .sum::<f64>();
+            if d2 > (CUT * r_s) * (CUT * r_s) {
+                return ap;
+            }
+        }
 
-        let d_ = p
-            .position
-            .iter()
-            .zip(n.center.iter())
-            .map(|(a, b)| (a - b) * (a - b))
-            .sum::<f64>()
-            .sqrt();
-        let d = f64::max(d_, self.epsilon);
-
-        if f64::sqrt(3f64) * n.size / d < self.theta || n.particule.is_some() {
+        let epsilon = self.pair_epsilon(p_id, n);
+        if n.particule.is_some() || self.accept_node(p, n, epsilon) {
+            *interactions += 1;
             let d_ = p
                 .position
                 .iter()
@@ -313,17 +1115,44 @@ impl Tree {
                 .map(|(a, b)| (a - b) * (a - b))
                 .sum::<f64>()
                 .sqrt();
-            let d = f64::max(d_, self.epsilon);
+            let (mut f, mut phi) = self.kernel.kernel(d_, epsilon);
+            if let Some(r_s) = self.split {
+                let (f_long, phi_long) = long_range(d_, r_s);
+                f -= f_long;
+                phi -= phi_long;
+            }
 
             for i in 0..3 {
-                ap[i] += n.mass / (d * d * d) * (n.center_of_mass[i] - p.position[i]);
+                ap[i] += n.mass * f * (n.center_of_mass[i] - p.position[i]);
+            }
+            ap[3] += n.mass * phi;
+
+            //higher order terms of the node (not with the short range part of the split)
+            if self.multipole_order >= 2 && n.particule.is_none() && self.split.is_none() {
+                //none inside the support of a compact kernel
+                if let Some(d) = self.kernel.multipole_distance(d_, epsilon) {
+                    let r = [
+                        p.position[0] - n.center_of_mass[0],
+                        p.position[1] - n.center_of_mass[1],
+                        p.position[2] - n.center_of_mass[2],
+                    ];
+                    let ap_ = multipole_acceleration(
+                        &r,
+                        d,
+                        &self.quadrupoles[node_id],
+                        self.octupoles.get(node_id),
+                    );
+                    for i in 0..4 {
+                        ap[i] += ap_[i];
+                    }
+                }
             }
-            ap[3] -= n.mass / d;
         } else {
             let kids = n.kids.clone();
             for kid in kids.iter() {
                 if kid.is_some() {
-                    let ap_ = self.compute_acceleration_rec(p_id, kid.unwrap() as usize);
+                    let ap_ =
+                        self.compute_acceleration_rec(p, p_id, kid.unwrap() as usize, interactions);
                     ap[0] += ap_[0];
                     ap[1] += ap_[1];
                     ap[2] += ap_[2];
@@ -334,13 +1163,152 @@ impl Tree {
         return ap;
     }
 
+    //acceleration and potential of the particule p_id from the tree,
+    //with the number of interactions used
+    pub fn tree_acceleration(&self, p_id: usize) -> ([f64; 4], usize) {
+        let mut interactions = 0;
+        let ap =
+            self.compute_acceleration_rec(&self.particules[p_id], Some(p_id), 0, &mut interactions);
+        (ap, interactions)
+    }
+
+    //acceleration and potential of massless probes at the positions, from the solver
+    //(with the analytic halo), the probes don't change the forces on the particules
+    //the probes walk the tree like the particules (with the fast multipole method too),
+    //the other solvers get them with the particules
+    pub fn probe_accelerations(&self, positions: &[[f64; 3]]) -> Vec<[f64; 4]> {
+        let probes: Vec<Particule> = positions
+            .iter()
+            .map(|x| Particule {
+                position: *x,
+                speed: [0., 0., 0.],
+                acceleration: [0., 0., 0.],
+                cinetic: 0f64,
+                potential: 0f64,
+                mass: 0f64,
+                component: Component::Cluster,
+            })
+            .collect();
+        let nb = self.particules.len();
+        let with_probes = || {
+            let mut particules = self.particules.clone();
+            particules.extend_from_slice(&probes);
+            particules
+        };
+        let walk = |p: &Particule| {
+            let mut interactions = 0;
+            self.compute_acceleration_rec(p, None, 0, &mut interactions)
+        };
+        let mut aps: Vec<[f64; 4]> = match self.solver {
+            Solver::Tree | Solver::Fmm(_) => probes.par_iter().map(walk).collect(),
+            Solver::Direct => {
+                let particules = with_probes();
+                let mut softenings = self.softenings.clone();
+                if !softenings.is_empty() {
+                    softenings.resize(particules.len(), self.epsilon);
+                }
+                (nb..particules.len())
+                    .into_par_iter()
+                    .map(|id| {
+                        direct_acceleration_of(
+                            &particules,
+                            id,
+                            self.kernel,
+                            self.epsilon,
+                            &softenings,
+                        )
+                    })
+                    .collect()
+            }
+            Solver::Pm(n) => pm_acceleration(&with_probes(), n, None, self.kernel, self.epsilon)
+                .0
+                .split_off(nb),
+            Solver::TreePm(n, factor) => {
+                let (mut aps, _) =
+                    pm_acceleration(&with_probes(), n, Some(factor), self.kernel, self.epsilon);
+                let mut aps = aps.split_off(nb);
+                aps.par_iter_mut()
+                    .zip(probes.par_iter())
+                    .for_each(|(ap, p)| {
+                        let short = walk(p);
+                        for i in 0..4 {
+                            ap[i] += short[i];
+                        }
+                    });
+                aps
+            }
+            Solver::Scf(scf) => scf.acceleration(&with_probes()).0.split_off(nb),
+            Solver::Spherical => {
+                spherical_acceleration(&with_probes(), self.kernel, self.epsilon).split_off(nb)
+            }
+        };
+        for (ap, x) in aps.iter_mut().zip(positions.iter()) {
+            let a = self.halo_acceleration(x);
+            for i in 0..3 {
+                ap[i] += a[i];
+            }
+        }
+        aps
+    }
+
+    //acceleration of the analytic halo at the position x (0 without halo)
+    pub fn halo_acceleration(&self, x: &[f64; 3]) -> [f64; 3] {
+        match self.halo {
+            Some(halo) => halo.acceleration(&[
+                x[0] - self.origin[0],
+                x[1] - self.origin[1],
+                x[2] - self.origin[2],
+            ]),
+            None => [0f64; 3],
+        }
+    }
+
     //update the acceleration and potential of all particules
     fn compute_acceleration(&mut self) {
         //vec of ([acceleration, potential])
-        let mut aps = vec![[0f64; 4]; self.particules.len()];
-        aps.par_iter_mut().enumerate().for_each(|(p_id, ap)| {
-            *ap = self.compute_acceleration_rec(p_id, 0).clone();
-        });
+        let aps = match self.solver {
+            Solver::Tree => {
+                let mut aps = vec![[0f64; 4]; self.particules.len()];
+                aps.par_iter_mut().enumerate().for_each(|(p_id, ap)| {
+                    *ap = self.tree_acceleration(p_id).0;
+                });
+                aps
+            }
+            Solver::Direct => direct_acceleration(
+                &self.particules,
+                self.kernel,
+                self.epsilon,
+                &self.softenings,
+            ),
+            Solver::Fmm(order) => {
+                let (aps, interactions) = fmm_acceleration(self, &Expansion::new(order));
+                self.fmm_interactions = interactions;
+                aps
+            }
+            Solver::Pm(n) => {
+                pm_acceleration(&self.particules, n, None, self.kernel, self.epsilon).0
+            }
+            Solver::TreePm(n, factor) => {
+                let (mut aps, r_s) =
+                    pm_acceleration(&self.particules, n, Some(factor), self.kernel, self.epsilon);
+                self.split = Some(r_s);
+                aps.par_iter_mut().enumerate().for_each(|(p_id, ap)| {
+                    let short = self.tree_acceleration(p_id).0;
+                    for i in 0..4 {
+                        ap[i] += short[i];
+                    }
+                });
+                aps
+            }
+            Solver::Scf(scf) => {
+                let (aps, coefficients) = scf.acceleration(&self.particules);
+                self.scf_coefficients = coefficients;
+                aps
+            }
+            Solver::Spherical => {
+                spherical_acceleration(&self.particules, self.kernel, self.epsilon)
+            }
+        };
         self.particules
             .par_iter_mut()
             .zip(aps.par_iter())
@@ -350,6 +1318,31 @@ impl Tree {
                 p.acceleration[2] = ap[2];
                 p.potential = ap[3];
             });
+
+        //add the acceleration of the analytic halo
+        //(its potential is not added to p.potential, see compute_energy)
+        if let Some(halo) = self.halo {
+            let origin = self.origin;
+            self.particules.par_iter_mut().for_each(|p| {
+                let x = [
+                    p.position[0] - origin[0],
+                    p.position[1] - origin[1],
+                    p.position[2] - origin[2],
+                ];
+                let a = halo.acceleration(&x);
+                p.acceleration[0] += a[0];
+                p.acceleration[1] += a[1];
+                p.acceleration[2] += a[2];
+            });
+        }
+    }
+
+    //return true if the particule is used for the inertia matrix and the density
+    pub fn is_selected(&self, p: &Particule) -> bool {
+        match self.restrict {
+            None => true,
+            Some(component) => p.component == component,
+        }
     }
 
     pub fn leap_frog(&mut self) {
@@ -359,12 +1352,40 @@ impl Tree {
             p.speed[0] += 0.5 * dt * p.acceleration[0];
             p.speed[1] += 0.5 * dt * p.acceleration[1];
             p.speed[2] += 0.5 * dt * p.acceleration[2];
-
-            p.position[0] += dt * p.speed[0];
-            p.position[1] += dt * p.speed[1];
-            p.position[2] += dt * p.speed[2];
         });
 
+        match &mut self.container {
+            None => self.particules.par_iter_mut().for_each(|p| {
+                p.position[0] += dt * p.speed[0];
+                p.position[1] += dt * p.speed[1];
+                p.position[2] += dt * p.speed[2];
+            }),
+            Some(container) => {
+                //the particules are reflected by the wall during the drift
+                let origin = self.origin;
+                let wall = *container;
+                let (crossings, impulse) = self
+                    .particules
+                    .par_iter_mut()
+                    .map(|p| {
+                        let mut x = [
+                            p.position[0] - origin[0],
+                            p.position[1] - origin[1],
+                            p.position[2] - origin[2],
+                        ];
+                        let (crossings, impulse) = wall.drift(&mut x, &mut p.speed, dt);
+                        for i in 0..3 {
+                            p.position[i] = origin[i] + x[i];
+                        }
+                        (crossings, p.mass * impulse)
+                    })
+                    .reduce(|| (0, 0.), |a, b| (a.0 + b.0, a.1 + b.1));
+                container.crossings += crossings;
+                container.impulse += impulse;
+                container.time += dt;
+            }
+        }
+
         self.rebuild_tree();
         self.compute_acceleration();
 
@@ -373,38 +1394,80 @@ impl Tree {
             p.speed[1] += 0.5 * dt * p.acceleration[1];
             p.speed[2] += 0.5 * dt * p.acceleration[2];
         });
+
+        if self.escaper_policy == EscaperPolicy::Remove {
+            self.remove_escapers();
+        }
     }
 
     //Compute [R10, R50, R90]
     pub fn compute_rayons(&mut self) {
-        let mut distances: Vec<f64> = self
-            .particules
-            .par_iter()
-            .map(|p| {
-                p.position
-                    .iter()
-                    .zip(self.center.iter())
-                    .map(|(p_i, c_i)| (p_i - c_i) * (p_i - c_i))
-                    .sum::<f64>()
-                    .sqrt()
-            })
-            .collect();
-        distances.sort_by(|a, b| a.partial_cmp(b).unwrap());
-
+        let distances = sorted_distances(&self.particules, &self.center);
         self.rayons = [
-            distances[distances.len() / 10],
-            distances[distances.len() / 2],
-            distances[distances.len() - distances.len() / 10],
+            distances[distances.len() / 10].0,
+            distances[distances.len() / 2].0,
+            distances[distances.len() - distances.len() / 10].0,
         ]
     }
 
-    //update epsilon
     //epsilon = (4/(3*N*pi))^(1/3) * R50  / lambda
-    pub fn compute_epsilon(&mut self) {
-        self.epsilon = (4f64 / (3f64 * self.particules.len() as f64 * std::f64::consts::PI))
-            .powf(1f64 / 3f64)
+    pub fn r50_epsilon(&self) -> f64 {
+        (4f64 / (3f64 * self.particules.len() as f64 * std::f64::consts::PI)).powf(1f64 / 3f64)
             * self.rayons[1]
-            / self.lambda;
+            / self.lambda
+    }
+
+    //update epsilon according to the softening policy, t in dynamical time
+    pub fn compute_epsilon(&mut self, t: f64) {
+        self.epsilon = match &self.softening {
+            SofteningPolicy::Fixed(Some(epsilon)) => *epsilon,
+            SofteningPolicy::Fixed(None) | SofteningPolicy::Adaptive => self.r50_epsilon(),
+            SofteningPolicy::Scheduled(schedule) => scheduled_epsilon(schedule, t),
+            SofteningPolicy::Individual => self.r50_epsilon(),
+        };
+        if self.softening == SofteningPolicy::Individual {
+            self.compute_softenings();
+        }
+    }
+
+    //softening of each particule, from the distance r_k to its k-th nearest neighbor
+    //epsilon_i = (4/(3*k*pi))^(1/3) * r_k / lambda, as epsilon with the sphere of the neighbors
+    pub fn compute_softenings(&mut self) {
+        let k = self.nb_neighbors;
+        let factor =
+            (4f64 / (3f64 * k as f64 * std::f64::consts::PI)).powf(1f64 / 3f64) / self.lambda;
+        let mut softenings = vec![0f64; self.particules.len()];
+        softenings
+            .par_iter_mut()
+            .enumerate()
+            .for_each(|(p_id, softening)| {
+                let mut neighbors = vec![(f64::INFINITY, p_id); k];
+                self.compute_local_density(p_id, 0, &mut neighbors);
+                *softening = factor * neighbors.last().unwrap().0.sqrt();
+            });
+        self.softenings = softenings;
+        self.compute_max_softening(0);
+    }
+
+    //update epsilon, and the accelerations and the energy if it changed
+    //return the jump of the energy caused by the new softening
+    pub fn update_epsilon(&mut self, t: f64) -> Option<f64> {
+        let epsilon = self.epsilon;
+        self.compute_epsilon(t);
+        if self.epsilon == epsilon && self.softenings.is_empty() {
+            return None;
+        }
+        let energy = self.energy;
+        self.compute_acceleration();
+        self.compute_energy();
+        Some(self.energy - energy)
+    }
+
+    //measure the pressure on the wall of the container, since the last measure
+    pub fn compute_wall_pressure(&mut self) {
+        if let Some(container) = &mut self.container {
+            container.measure_pressure();
+        }
     }
 
     //compute total energy and virial and update cinetic energy of each particules
@@ -415,14 +1478,34 @@ impl Tree {
         });
 
         let e_c: f64 = self.particules.par_iter().map(|p| p.cinetic).sum();
-        let e_p: f64 = self
+        let mut e_p: f64 = self
             .particules
             .par_iter()
             .map(|p| p.mass * 0.5 * p.potential)
             .sum();
+        //potential energy in the analytic halo
+        if let Some(halo) = self.halo {
+            e_p += self
+                .particules
+                .par_iter()
+                .map(|p| {
+                    let r = p
+                        .position
+                        .iter()
+                        .zip(self.origin.iter())
+                        .map(|(x, o)| (x - o) * (x - o))
+                        .sum::<f64>()
+                        .sqrt();
+                    p.mass * halo.potential(r)
+                })
+                .sum::<f64>();
+        }
 
         self.energy = e_c + e_p;
         self.virial = 2. * e_c / e_p;
+        if let Some(container) = &self.container {
+            self.wall_virial = (2. * e_c - 3. * container.pressure * container.volume()) / e_p;
+        }
     }
 
     //update dt and dynamical time
@@ -473,37 +1556,43 @@ impl Tree {
         dmin <= r
     }
 
-    //compute the local density of the particule p_id
-    //for the calculation of the center of density
+    //find the nearest neighbors of the particule p_id (the particule itself included)
+    //for the calculation of the local density, of the softenings and for the resampling
     //it use the octree to reduce the complexity of finding the k-nearest-neighbor
-    fn compute_local_density(&self, p_id: usize, node_id: usize, radii: &mut Vec<f64>) {
+    //neighbors holds the k nearest (squared distance, id) found, sorted by distance
+    fn compute_local_density(
+        &self,
+        p_id: usize,
+        node_id: usize,
+        neighbors: &mut Vec<(f64, usize)>,
+    ) {
         let p = &self.particules[p_id];
         let n = &self.nodes[node_id];
 
         //if every points in this subspace is to far from the particule we don't need to explore it
-        if !Tree::sphere_touch_node(n, p, *radii.last().unwrap()) {
+        if !Tree::sphere_touch_node(n, p, neighbors.last().unwrap().0) {
             return;
         }
 
-        if n.particule.is_some() {
+        if let Some(id) = n.particule {
             //if this node is a leaf, we check if the particule is a k-NN
-            let p_2 = &self.particules[n.particule.unwrap() as usize];
+            let p_2 = &self.particules[id as usize];
             let d = p
                 .position
                 .iter()
                 .zip(p_2.position.iter())
                 .map(|(p1, p2)| (p1 - p2) * (p1 - p2))
                 .sum();
-            if d < *radii.last().unwrap() {
-                radii.push(d);
-                radii.sort_by(|a, b| a.partial_cmp(b).unwrap());
-                radii.pop();
+            if d < neighbors.last().unwrap().0 {
+                neighbors.push((d, id as usize));
+                neighbors.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+                neighbors.pop();
             }
         } else {
             //else we first explore the branch of the tree that contains the particule
             let subtree = self.get_subtree_id(node_id, p_id);
             if n.kids[subtree].is_some() {
-                self.compute_local_density(p_id, n.kids[subtree].unwrap() as usize, radii);
+                self.compute_local_density(p_id, n.kids[subtree].unwrap() as usize, neighbors);
             }
 
             //then we explore the others branches
@@ -513,11 +1602,30 @@ impl Tree {
                 .enumerate()
                 .filter(|(i, k)| *i != subtree && k.is_some())
             {
-                self.compute_local_density(p_id, kid.unwrap() as usize, radii);
+                self.compute_local_density(p_id, kid.unwrap() as usize, neighbors);
             }
         }
     }
 
+    //ids of the k nearest neighbors of every particule (the particule itself excluded)
+    //the tree is built around all the particules, it must be built again after
+    pub fn nearest_neighbors(&mut self, k: usize) -> Vec<Vec<usize>> {
+        self.build(RootSize::BoundingBox);
+        (0..self.particules.len())
+            .into_par_iter()
+            .map(|p_id| {
+                let mut neighbors = vec![(f64::INFINITY, p_id); k + 1];
+                self.compute_local_density(p_id, 0, &mut neighbors);
+                neighbors
+                    .iter()
+                    .filter(|(d, id)| *id != p_id && d.is_finite())
+                    .take(k)
+                    .map(|(_, id)| *id)
+                    .collect()
+            })
+            .collect()
+    }
+
     //Compute the center of density
     pub fn compute_center(&mut self) {
         let k = self.nb_neighbors;
@@ -528,9 +1636,9 @@ impl Tree {
             .into_par_iter()
             .zip(densites.par_iter_mut())
             .for_each(|(p_id, d)| {
-                let mut radii = vec![std::f64::INFINITY; k];
-                self.compute_local_density(p_id, 0, &mut radii);
-                let r_sq = radii.last().unwrap();
+                let mut neighbors = vec![(f64::INFINITY, p_id); k];
+                self.compute_local_density(p_id, 0, &mut neighbors);
+                let r_sq = neighbors.last().unwrap().0;
                 *d = 1. / (r_sq * r_sq.sqrt());
             });
 
@@ -561,10 +1669,12 @@ impl Tree {
         self.center = center;
     }
 
+    //inertia matrix of the selected particules (see restrict)
     pub fn compute_inertia_matrix(&mut self) {
         let a: f64 = self
             .particules
             .par_iter()
+            .filter(|p| self.is_selected(p))
             .map(|p| {
                 (p.position[1] - self.center[1]).powf(2f64)
                     + (p.position[2] - self.center[2]).powf(2f64)
@@ -573,6 +1683,7 @@ impl Tree {
         let b: f64 = self
             .particules
             .par_iter()
+            .filter(|p| self.is_selected(p))
             .map(|p| {
                 (p.position[0] - self.center[0]).powf(2f64)
                     + (p.position[2] - self.center[2]).powf(2f64)
@@ -581,6 +1692,7 @@ impl Tree {
         let c: f64 = self
             .particules
             .par_iter()
+            .filter(|p| self.is_selected(p))
             .map(|p| {
                 (p.position[1] - self.center[1]).powf(2f64)
                     + (p.position[0] - self.center[0]).powf(2f64)
@@ -589,16 +1701,19 @@ impl Tree {
         let d: f64 = self
             .particules
             .par_iter()
+            .filter(|p| self.is_selected(p))
             .map(|p| (p.position[0] - self.center[0]) * (p.position[1] - self.center[1]))
             .sum();
         let e: f64 = self
             .particules
             .par_iter()
+            .filter(|p| self.is_selected(p))
             .map(|p| (p.position[0] - self.center[0]) * (p.position[2] - self.center[2]))
             .sum();
         let f: f64 = self
             .particules
             .par_iter()
+            .filter(|p| self.is_selected(p))
             .map(|p| (p.position[1] - self.center[1]) * (p.position[2] - self.center[2]))
             .sum();
 
@@ -615,3 +1730,104 @@ impl Tree {
         self.inertia_matrix[8] = c;
     }
 }
+
+//distances of the particules to a point, with their ids, sorted by distance
+fn sorted_distances(particules: &[Particule], point: &[f64; 3]) -> Vec<(f64, usize)> {
+    let mut distances: Vec<(f64, usize)> = particules
+        .par_iter()
+        .enumerate()
+        .map(|(p_id, p)| {
+            let d = p
+                .position
+                .iter()
+                .zip(point.iter())
+                .map(|(p_i, c_i)| (p_i - c_i) * (p_i - c_i))
+                .sum::<f64>()
+                .sqrt();
+            (d, p_id)
+        })
+        .collect();
+    distances.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+    distances
+}
+
+//acceleration and potential of the shell code: each particule feels the mass inside
+//its radius as a point mass at the center of mass, and the mass outside as shells
+//phi_i = M(<r_i) phi(r_i) + sum_(r_j > r_i) m_j phi(r_j), a = -M(<r) f(r) x with the
+//softening kernel (phi = -1 / r and f = 1 / r^3 without softening), so each pair has the
+//energy -m_i m_j phi(max(r_i, r_j)) and the potential is continuous when two shells cross
+//the forces are radial, so the angular momentum of each particule is conserved
+fn spherical_acceleration(particules: &[Particule], kernel: Kernel, epsilon: f64) -> Vec<[f64; 4]> {
+    let (center, _) = center_of_mass(particules);
+    let distances = sorted_distances(particules, &center);
+    //the kernel is singular at the center without softening
+    let softened = |d: f64| {
+        if d > 0. || epsilon > 0. {
+            kernel.kernel(d, epsilon)
+        } else {
+            (0., 0.)
+        }
+    };
+    //mass inside each particule, and potential of the shells outside
+    let mut inside = vec![0f64; distances.len()];
+    let mut mass = 0.;
+    for (k, &(_, p_id)) in distances.iter().enumerate() {
+        inside[k] = mass;
+        mass += particules[p_id].mass;
+    }
+    let mut outside = vec![0f64; distances.len()];
+    let mut potential = 0.;
+    for (k, &(d, p_id)) in distances.iter().enumerate().rev() {
+        outside[k] = potential;
+        potential += particules[p_id].mass * softened(d).1;
+    }
+    let mut aps = vec![[0f64; 4]; particules.len()];
+    for (k, &(d, p_id)) in distances.iter().enumerate() {
+        let p = &particules[p_id];
+        let (f, phi) = softened(d);
+        for i in 0..3 {
+            aps[p_id][i] = -inside[k] * f * (p.position[i] - center[i]);
+        }
+        aps[p_id][3] = inside[k] * phi + outside[k];
+    }
+    aps
+}
+
+//tree of a plummer sphere of 2000 particules (seed 42, plummer kernel), for the tests of the solvers
+#[cfg(test)]
+pub fn plummer_tree(solver: Solver, multipole_order: usize, theta: f64) -> Tree {
+    Tree::new_tree(Parameters {
+        nb: 2000,
+        nb_save: 0,
+        mu: 150.,
+        lambda: 1.,
+        targets: Targets {
+            virial: None,
+            energy: None,
+            r50: None,
+            sigma_c: None,
+            mass: None,
+        },
+        theta,
+        model: Model::Plummer,
+        seed: 42,
+        nb_bins: 100,
+        nb_neighbors: 32,
+        mu_init: 150.,
+        theta_init: theta,
+        barycentric: true,
+        keep_centered: false,
+        restrict: None,
+        root_size: RootSize::BoundingBox,
+        escaper_policy: EscaperPolicy::Keep,
+        container_radius: None,
+        multipole_order,
+        opening: Opening::Geometric,
+        kernel: Kernel::Plummer,
+        softening: SofteningPolicy::Fixed(None),
+        solver,
+        resampling: None,
+        perturbation: None,
+        binaries: None,
+    })
+}
@@ -1,3 +1,7 @@
+use crate::binaries::*;
+use crate::perturbation::*;
+use crate::scaling::*;
+use crate::scf::*;
 use crate::tree::*;
 use rayon::prelude::*;
 use std::fs::File;
@@ -5,7 +9,7 @@ use std::io::Write;
 
 pub fn write_positions(tree: &Tree, file_name: String) {
     let mut file = File::create(file_name).unwrap();
-    for i in 0..tree.nb_save {
+    for i in 0..usize::min(tree.nb_save, tree.particules.len()) {
         write!(
             &mut file,
             "{};{};{};",
@@ -27,7 +31,7 @@ pub fn write_positions(tree: &Tree, file_name: String) {
 // Added to the original code
 pub fn write_velocities(tree: &Tree, file_name: String) {
     let mut file = File::create(file_name).unwrap();
-    for i in 0..tree.nb_save {
+    for i in 0..usize::min(tree.nb_save, tree.particules.len()) {
         write!(
             &mut file,
             "{};{};{}\n",
@@ -39,8 +43,53 @@ pub fn write_velocities(tree: &Tree, file_name: String) {
     }
 }
 
-pub fn write_infos(infos: &Vec<Vec<f64>>, inertia_matrices: &Vec<[f64; 9]>, folder_name: String) {
+//write the catalogue of the primordial binaries
+pub fn write_binaries_catalogue(tree: &Tree, file_name: String) {
+    let mut file = File::create(file_name).unwrap();
+    for binary in tree.binaries.iter() {
+        writeln!(
+            &mut file,
+            "{};{};{};{};{};{};{}",
+            binary.id,
+            binary.ids[0],
+            binary.ids[1],
+            tree.particules[binary.ids[0]].mass,
+            tree.particules[binary.ids[1]].mass,
+            binary.semi_major_axis,
+            binary.eccentricity
+        )
+        .unwrap();
+    }
+}
+
+//write the current semi-major axis and eccentricity of the primordial binaries
+//with the row of the binary in the catalogue (a < 0 if the binary is unbound,
+//the binaries with a removed star are not written)
+pub fn write_binaries(tree: &Tree, file_name: String) {
+    let mut file = File::create(file_name).unwrap();
+    for binary in tree.binaries.iter() {
+        let (a, e) = orbital_elements(
+            &tree.particules[binary.ids[0]],
+            &tree.particules[binary.ids[1]],
+        );
+        writeln!(&mut file, "{};{};{}", binary.id, a, e).unwrap();
+    }
+}
+
+pub fn write_infos(
+    infos: &[Vec<f64>],
+    inertia_matrices: &[[f64; 9]],
+    scaling: &Scaling,
+    folder_name: String,
+) {
     let mut file = File::create(format!("{}/infos.csv", folder_name)).unwrap();
+    //header with the values of the initial conditions after the scaling
+    writeln!(
+        &mut file,
+        "#mass={};r50={};virial={};energy={};sigma_c={}",
+        scaling.mass, scaling.r50, scaling.virial, scaling.energy, scaling.sigma_c
+    )
+    .unwrap();
     for info in infos.iter() {
         for i in info {
             write!(&mut file, "{};", i).unwrap();
@@ -56,12 +105,117 @@ pub fn write_infos(infos: &Vec<Vec<f64>>, inertia_matrices: &Vec<[f64; 9]>, fold
     }
 }
 
-//compute the density profile and then write it to file
+//write the errors of the forces compared to the exact shells
+//t;median, 99th percentile and max of the relative error of the acceleration;same for the potential
+//then the same for the massless probes in the cavity of the shells, if there is one
+pub fn write_shells_errors(errors: &[Vec<f64>], folder_name: String) {
+    let mut file = File::create(format!("{}/shells_errors.csv", folder_name)).unwrap();
+    for error in errors.iter() {
+        for e in error {
+            write!(&mut file, "{};", e).unwrap();
+        }
+        writeln!(&mut file).unwrap();
+    }
+}
+
+//write the measures on the wall of the container
+//t;number of crossings;pressure;virial ratio with the wall
+pub fn write_container(walls: &[Vec<f64>], radius: f64, folder_name: String) {
+    let mut file = File::create(format!("{}/container.csv", folder_name)).unwrap();
+    writeln!(&mut file, "#radius={}", radius).unwrap();
+    for wall in walls.iter() {
+        for x in wall {
+            write!(&mut file, "{};", x).unwrap();
+        }
+        writeln!(&mut file).unwrap();
+    }
+}
+
+//write the errors of the accelerations compared to the direct summation
+//t;median;99th percentile;max;interactions per particule
+pub fn write_force_errors(forces: &[Vec<f64>], folder_name: String) {
+    let mut file = File::create(format!("{}/force_errors.csv", folder_name)).unwrap();
+    for force in forces.iter() {
+        for x in force {
+            write!(&mut file, "{};", x).unwrap();
+        }
+        writeln!(&mut file).unwrap();
+    }
+}
+
+//write the changes of epsilon
+//t;old epsilon;new epsilon;energy jump
+pub fn write_softenings(softenings: &[Vec<f64>], folder_name: String) {
+    let mut file = File::create(format!("{}/softening.csv", folder_name)).unwrap();
+    for softening in softenings.iter() {
+        for x in softening {
+            write!(&mut file, "{};", x).unwrap();
+        }
+        writeln!(&mut file).unwrap();
+    }
+}
+
+//write the particules removed from the simulation
+//t;id;mass;energy per unit mass;vx;vy;vz
+pub fn write_escapers(escapers: &[(f64, Escaper)], folder_name: String) {
+    let mut file = File::create(format!("{}/escapers.csv", folder_name)).unwrap();
+    for (t, e) in escapers.iter() {
+        writeln!(
+            &mut file,
+            "{};{};{};{};{};{};{}",
+            t, e.id, e.mass, e.energy, e.speed[0], e.speed[1], e.speed[2]
+        )
+        .unwrap();
+    }
+}
+
+//write the projections of the snapshots on the mode of the perturbation
+//t;density amplitude;velocity amplitude
+pub fn write_modes(modes: &[Vec<f64>], perturbation: &Perturbation, folder_name: String) {
+    let mut file = File::create(format!("{}/modes.csv", folder_name)).unwrap();
+    writeln!(
+        &mut file,
+        "#l={};m={};mode={:?};amplitude={}",
+        perturbation.l, perturbation.m, perturbation.mode, perturbation.amplitude
+    )
+    .unwrap();
+    for mode in modes.iter() {
+        for x in mode {
+            write!(&mut file, "{};", x).unwrap();
+        }
+        writeln!(&mut file).unwrap();
+    }
+}
+
+//write the coefficients A_nlm of the SCF expansion, with the scale of the basis: n;l;m;A_nlm
+//(m < 0 for the sin(|m| phi) terms)
+pub fn write_scf_coefficients(tree: &Tree, scf: &Scf, file_name: String) {
+    let mut file = File::create(file_name).unwrap();
+    writeln!(&mut file, "scale;{}", scf.scale.unwrap()).unwrap();
+    for n in 0..=scf.nmax {
+        for l in 0..=scf.lmax {
+            for m in -(l as i64)..=l as i64 {
+                writeln!(
+                    &mut file,
+                    "{};{};{};{}",
+                    n,
+                    l,
+                    m,
+                    tree.scf_coefficients[scf.index(n, l, m)]
+                )
+                .unwrap();
+            }
+        }
+    }
+}
+
+//compute the density profile of the selected particules (see restrict) and then write it to file
 pub fn write_density(tree: &Tree, file_name: String) {
     //compute and sort distances
     let mut distances: Vec<f64> = tree
         .particules
         .par_iter()
+        .filter(|p| tree.is_selected(p))
         .map(|p| {
             f64::sqrt(
                 (0..3)
@@ -76,7 +230,7 @@ pub fn write_density(tree: &Tree, file_name: String) {
     let number_of_bins = tree.nb_bins;
     let mut bins = vec![0f64; number_of_bins];
     let mut bins_radii = vec![0f64; number_of_bins];
-    let bin_size = tree.particules.len() / number_of_bins;
+    let bin_size = distances.len() / number_of_bins;
     let mut last_radius = 0f64;
 
     //compute the density of each slices of bin_size particules
@@ -84,7 +238,7 @@ pub fn write_density(tree: &Tree, file_name: String) {
         bins_radii[i] = distances[(i + 1) * bin_size];
         let volume =
             4. / 3. * std::f64::consts::PI * (bins_radii[i].powf(3f64) - last_radius.powf(3f64));
-        bins[i] = bin_size as f64 / volume / tree.particules.len() as f64;
+        bins[i] = bin_size as f64 / volume / distances.len() as f64;
         last_radius = bins_radii[i];
     }
 